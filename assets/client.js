@@ -7,9 +7,18 @@ window.elem = {
   board: document.getElementById("board"),
   cursor_frame: document.getElementById("cursor-frame"),
   volslider: document.getElementById("volslider"),
-  chat_div: document.getElementById("chat-div")
+  chat_div: document.getElementById("chat-div"),
+  hostctl: document.getElementById("hostctl"),
+  hostreset: document.getElementById("hostreset"),
+  settingsform: document.getElementById("settingsform"),
+  scoreboard: document.getElementById("scoreboard"),
+  racefeed: document.getElementById("racefeed"),
+  turnfeed: document.getElementById("turnfeed"),
+  lives: document.getElementById("lives")
 };
 const U32MAX = Math.pow(2,32) - 1;
+// first byte of binary position updates, see livepos::BIN_POS_TAG
+const POS_TAG = 0xff;
 
 window.queued_pos = undefined;
 window.assets = {
@@ -45,6 +54,10 @@ window.room = {
   last_packet: undefined,
   identity: JSON.parse(localStorage.getItem("identity")),
   cursors: new Map(),
+  spectating: window.location.hash == "#spectate",
+  last_err: undefined,
+  turn: undefined,
+  view: undefined,
 };
 
 
@@ -55,7 +68,8 @@ if (room.identity == null) {
   join();
 }
 
-function join() {
+function join(spectate) {
+  if (spectate) { room.spectating = true; }
   if (room.identity == null) {
     room.identity = {};
     room.identity.name = document.getElementById("name-in").value;
@@ -74,47 +88,56 @@ function clear_ident() {
 function connect() {
   let wsproto = (window.location.protocol == "https:")? "wss:": "ws:";
   let s = new WebSocket(`${wsproto}//${location.hostname}:${location.port}${location.pathname}/ws`);
-  s.onopen = function() {
-    s.send(`register ${room.identity.name} ${room.identity.clr}`);
+  let register = function() {
+    let verb = room.spectating? "spectate" : "register";
+    s.send("posfmt bin");
+    s.send(`${verb} ${room.identity.name} ${room.identity.clr}`);
   }
+  s.onopen = register;
   s.onmessage = function(e) {
     room.last_packet = e;
     let d = e.data;
     if (typeof d == "object") {
-      d.arrayBuffer().then(acceptBoard);
-      elem.info.onclick = undefined;
-      elem.info.innerHTML = `${room.name} (${room.bconf.w}x${room.bconf.h}) >> Running, ${room.bconf.mine_ratio} tiles are mines`;
+      d.arrayBuffer().then(buf => {
+        let bytes = new Uint8Array(buf);
+        if (bytes[0] == POS_TAG) {
+          acceptPos(bytes);
+        } else {
+          acceptBoard(buf);
+          showRunning();
+        }
+      });
     } else if (typeof e.data == "string") {
       let fields = d.split(" ");
       switch (fields[0]) {
         case "pos": {
-          let posdata = JSON.parse(fields[1]);
-          posdata.forEach(pdat => {
-            let oid = Number(pdat[0]);
-            let x = pdat[1][0];
-            let y = pdat[1][1];
-            let curs = room.cursors.get(oid);
-            if (oid != player.uid) {
-              if (curs != undefined) {
-                movCursor(curs, [x, y]);
-              } else {
-                console.log("livepos sys incoherent");
-              }
-            }
-          });
+          JSON.parse(fields[1]).forEach(pdat => moveOther(Number(pdat[0]), pdat[1][0], pdat[1][1]));
         } break;
         case "players": {
           let pdata = JSON.parse(fields[1]);
-          console.log(pdata);
-          pdata.forEach(p => {
+          room.host = pdata.host;
+          pdata.list.forEach(p => {
             let oid = Number(p[0]);
             let name = p[1];
             let clr = p[2];
-            console.log(oid, name, clr);
             if (!room.cursors.has(oid)) {
               createCursor(oid, name, clr);
             }
+            room.cursors.get(oid).team = p[3];
           });
+          labelCursors();
+          let hosting = room.host == player.uid;
+          elem.hostctl.style.display = hosting? "" : "none";
+          elem.hostreset.checked = pdata.hostreset;
+        } break;
+        case "bconf": {
+          let dims = fields[1].split("x");
+          room.bconf.w = Number(dims[0]);
+          room.bconf.h = Number(dims[1]);
+          room.bconf.mine_ratio = fields[2];
+        } break;
+        case "nextconf": {
+          elem.info.innerHTML = `The host changed the board to ${fields[1]}, ${fields[2]} mined, starting next game`;
         } break;
         case "regack": {
           room.name = fields[1];
@@ -124,36 +147,194 @@ function connect() {
           room.bconf.w = Number(dims[0]);
           room.bconf.h = Number(dims[1]);
           room.bconf.mine_ratio = fields[5];
-          createCursor(player.uid, name, room.identity.clr);
+          room.spectating = fields[6] == "spectator";
+          if (!room.spectating) {
+            createCursor(player.uid, name, room.identity.clr);
+          }
+        } break;
+        case "pwreq": {
+          let why = (room.last_err)? `${room.last_err}, try again` : "this room needs a password";
+          let pw = prompt(why);
+          if (pw == null) {
+            s.close();
+          } else {
+            room.password = pw;
+            s.send(`auth ${pw}`);
+          }
+        } break;
+        case "authack": {
+          room.last_err = undefined;
+          register();
+        } break;
+        case "err": {
+          room.last_err = fields.slice(1).join(" ");
+          elem.info.innerHTML = room.last_err;
         } break;
         case "win": {
-          elem.info.innerHTML = "You win! Click here to play again.";
+          let res = JSON.parse(fields.slice(1).join(" "));
+          if (res.team !== undefined) {
+            let mine = room.cursors.get(player.uid);
+            let verdict = (res.team == null)? "It's a draw!" : `Team ${res.team} wins${(mine && mine.team == res.team)? ", that's you" : ""}!`;
+            elem.info.innerHTML = `${verdict} Click here to play again.${replayLink(res)}`;
+          } else {
+            elem.info.innerHTML = `You win! ${gameNumbers(res)}. Click here to play again.${replayLink(res)}`;
+          }
           elem.info.onclick = e => { s.send("reset") };
         } break;
         case "lose": {
-          let badone = fields[1];
-          elem.info.innerHTML = `You lost, ${badone} was blown up. Click here to retry.`;
+          let res = JSON.parse(fields.slice(1).join(" "));
+          let how = (res.cause == "flags")? "ran out of lives on a wrong flag" : "was blown up";
+          elem.info.innerHTML = `You lost, ${res.player} ${how}. ${gameNumbers(res)}. Click here to retry.${replayLink(res)}`;
           elem.info.onclick = e => { s.send("reset") };
+          if (res.cause != "flags") { assets.audio.explosion.data.play(); }
+        } break;
+        case "score": {
+          let rows = JSON.parse(fields.slice(1).join(" "));
+          let head = "<tr><th>player</th><th>revealed</th><th>good flags</th><th>bad flags</th><th>explosions</th></tr>";
+          elem.scoreboard.innerHTML = head + rows.map(r =>
+            `<tr><td>${r.name}</td><td>${r.revealed}</td><td>${r.flags_correct}</td><td>${r.flags_wrong}</td><td>${r.explosions}</td></tr>`
+          ).join("");
+          elem.scoreboard.style.display = "";
+        } break;
+        case "progress": {
+          let rows = JSON.parse(fields.slice(1).join(" "));
+          elem.racefeed.innerHTML = rows.map(r => {
+            let mark = { won: "🏁", dead: "💥", alive: "" }[r.state];
+            return `<span>${r.name} ${r.percent.toFixed(0)}%${mark}</span>`;
+          }).join(" · ");
+        } break;
+        case "ranking": {
+          let rows = JSON.parse(fields.slice(1).join(" "));
+          let head = "<tr><th>#</th><th>player</th><th>cleared</th><th>time</th></tr>";
+          elem.scoreboard.innerHTML = head + rows.map((r, i) =>
+            `<tr><td>${i+1}</td><td>${r.name}</td><td>${r.percent.toFixed(0)}%</td><td>${(r.state == "won")? r.time.toFixed(2) + "s" : r.state}</td></tr>`
+          ).join("");
+          elem.scoreboard.style.display = "";
+          elem.info.innerHTML = "Race over! Click here for another.";
+          elem.info.onclick = e => { s.send("reset") };
+        } break;
+        case "view": {
+          // big boards and endless worlds only show a part, the full size comes along for boards
+          room.view = (fields[1] == "-")? undefined : {
+            x: Number(fields[1]), y: Number(fields[2]), w: Number(fields[3]), h: Number(fields[4]),
+            W: (fields[5] == undefined)? undefined : Number(fields[5]),
+            H: (fields[6] == undefined)? undefined : Number(fields[6]),
+          };
+        } break;
+        case "delta": {
+          // just the bytes of the last board that changed
+          if (room.raw) {
+            JSON.parse(fields[1]).forEach(([i, c]) => { if (i < room.raw.length) { room.raw[i] = c; } });
+            renderBoard();
+          }
+        } break;
+        case "blast": {
+          let b = JSON.parse(fields.slice(1).join(" "));
+          elem.turnfeed.innerHTML = `${b.player} blew up at ${b.x},${b.y}, everything around there starts over`;
           assets.audio.explosion.data.play();
         } break;
+        case "lives": {
+          elem.lives.innerHTML = "♥".repeat(Number(fields[1])) || "💀";
+        } break;
+        case "teamscore": {
+          let t = JSON.parse(fields[1]);
+          let mine = room.cursors.get(player.uid);
+          let yours = (mine && mine.team)? ` (you're on ${mine.team})` : "";
+          elem.racefeed.innerHTML = `Team A ${t.A} · Team B ${t.B} — ${Math.floor(t.mines/2)+1} of ${t.mines} mines wins${yours}`;
+        } break;
+        case "turn": {
+          room.turn = (fields[1] == "-")? undefined : Number(fields[1]);
+          let left = (fields[2] == "-")? "" : ` (${fields[2]}s)`;
+          if (room.turn == undefined) {
+            elem.turnfeed.innerHTML = "";
+          } else if (room.turn == player.uid) {
+            elem.turnfeed.innerHTML = `Your turn!${left}`;
+          } else {
+            let c = room.cursors.get(room.turn);
+            elem.turnfeed.innerHTML = `${c? c.name : "someone"}'s turn${left}`;
+          }
+          labelCursors();
+        } break;
         case "logoff": {
           let oid = Number(fields[1]);
-          room.cursors.get(oid).elem.remove();
-          room.cursors.get(oid).selwin.remove();
-          room.cursors.delete(oid);
+          let curs = room.cursors.get(oid);
+          if (curs != undefined) {
+            curs.elem.remove();
+            curs.selwin.remove();
+            room.cursors.delete(oid);
+          }
         } break;
       }
     }
   }
   s.onerror = function(e) { elem.info.innerHTML += `<br>Connection error: ${e}`; }
-  s.onclose = function(e) { elem.info.innerHTML = "Connection closed"; }
+  s.onclose = function(e) {
+    elem.info.innerHTML = "Connection closed" + (room.last_err? `: ${room.last_err}` : "");
+  }
   return s;
 }
 
+function labelCursors() {
+  room.cursors.forEach((c, oid) => {
+    let team = c.team? `[${c.team}]` : "";
+    c.elem.firstChild.innerHTML = ((oid == room.host)? "★" : "") + ((oid == room.turn)? "⏳" : "") + team + c.name;
+  });
+}
+
+function replayLink(res) {
+  if (res.replay == undefined) { return ""; }
+  let pw = (room.password == undefined)? "" : `?pw=${encodeURIComponent(room.password)}`;
+  return ` <a href="${location.pathname}/replays/${res.replay}${pw}" download onclick="event.stopPropagation()">Download replay</a>`;
+}
+
+function gameNumbers(res) {
+  return `${res.time.toFixed(2)}s, 3BV ${res.bbbv} (${res.bbbv_per_s.toFixed(2)}/s), ${res.clicks} clicks, ${res.efficiency.toFixed(0)}% efficient`;
+}
+
+// binary positions: after the tag, a varint uid and 16 bit x and y per cursor
+function acceptPos(bytes) {
+  let i = 1;
+  while (i < bytes.length) {
+    let oid = 0, shift = 0, b;
+    do {
+      b = bytes[i++];
+      oid += (b & 0x7f) * Math.pow(2, shift);
+      shift += 7;
+    } while (b & 0x80);
+    let x = bytes[i] | (bytes[i+1] << 8);
+    let y = bytes[i+2] | (bytes[i+3] << 8);
+    i += 4;
+    // middle of the step, back to board coords
+    moveOther(oid, x * 65536 + 32768, y * 65536 + 32768);
+  }
+}
+
+function moveOther(oid, x, y) {
+  if (oid == player.uid) { return; }
+  let curs = room.cursors.get(oid);
+  if (curs != undefined) {
+    movCursor(curs, fromWhole([x, y]));
+  } else {
+    console.log("livepos sys incoherent");
+  }
+}
+
+function showRunning() {
+  elem.scoreboard.style.display = "none";
+  elem.info.onclick = undefined;
+  let watching = room.spectating? " (spectating)" : "";
+  let where = room.view? ` at ${room.view.x},${room.view.y} (arrows or WASD to move around)` : "";
+  elem.info.innerHTML = `${room.name} (${room.bconf.w}x${room.bconf.h}) >> Running, ${room.bconf.mine_ratio} tiles are mines${where}${watching}`;
+}
+
 function acceptBoard(data) {
   let dataarr = new Uint8Array(data);
-  let vals = fflate.inflateSync(dataarr);
-  room.board = vals.reduce((s,c) => {
+  room.raw = fflate.inflateSync(dataarr);
+  renderBoard();
+}
+
+function renderBoard() {
+  room.board = room.raw.reduce((s,c) => {
     let v = String.fromCodePoint(c);
     if (v == ' ') {
       s = s + "&nbsp";
@@ -167,7 +348,7 @@ function acceptBoard(data) {
   let split_board = [];
   for (let i = 1; i < room.board.length+1; i++) {
     let cur = room.board[i];
-    let gamechars = /^[CFQO# 1-8]+$/;
+    let gamechars = /^[ABCFQO#~ 1-8]+$/;
     if ((cur != last && gamechars.test(cur)) || cur == undefined) {
       let txt = room.board.substr(last_idx, i-last_idx);
       switch(txt[0]) {
@@ -184,6 +365,15 @@ function acceptBoard(data) {
           txt = txt.replaceAll("Q", "?");
           txt = `<span style="color:purple;">${txt}</span>`;
           break;
+        case '~':
+          txt = `<span style="color:#555;">${txt}</span>`;
+          break;
+        case 'A':
+          txt = `<span style="color:deepskyblue;">${txt}</span>`;
+          break;
+        case 'B':
+          txt = `<span style="color:orange;">${txt}</span>`;
+          break;
 
         case '1': txt = `<span style="color:#0100FB;">${txt}</span>`; break;
         case '2': txt = `<span style="color:#027F01;">${txt}</span>`; break;
@@ -228,7 +418,7 @@ function createCursor(id, name, clr) {
     },
       false);
   }
-  room.cursors.set(id, {name: name, elem: cursor, selwin: selection_window});
+  room.cursors.set(id, c);
   return cursor;
 }
 
@@ -281,18 +471,38 @@ window.onresize = () => {
 }
 
 elem.bcont.onclick = function(e) {
+  if (room.spectating) { return; }
   let bcoords = pageToBoard(e.pageX, e.pageY);
   let tpos = tilepos(bcoords);
   let cmd = `reveal ${tpos[0]} ${tpos[1]}`;
   room.socket.send(cmd);
 }
 elem.bcont.oncontextmenu = function(e) {
+  if (room.spectating) { return false; }
   let bcoords = pageToBoard(e.pageX, e.pageY);
   let tpos = tilepos(bcoords);
   let cmd = `flag ${tpos[0]} ${tpos[1]}`;
   room.socket.send(cmd);
   return false;
 }
+// cursors travel in whole board coords, the view only shows part of it
+function toWhole(b) {
+  let v = room.view;
+  if (!v || v.W == undefined) { return b; }
+  return [
+    Math.floor((v.x + v.w * b[0]/U32MAX) / v.W * U32MAX),
+    Math.floor((v.y + v.h * b[1]/U32MAX) / v.H * U32MAX)
+  ];
+}
+function fromWhole(b) {
+  let v = room.view;
+  if (!v || v.W == undefined) { return b; }
+  return [
+    Math.floor((v.W * b[0]/U32MAX - v.x) / v.w * U32MAX),
+    Math.floor((v.H * b[1]/U32MAX - v.y) / v.h * U32MAX)
+  ];
+}
+
 // these are board coords, [0..2**32)
 function tilepos(b) {
   return [
@@ -301,6 +511,43 @@ function tilepos(b) {
   ];
 }
 
+// endless worlds: look around a few tiles at a time
+document.addEventListener('keydown', e => {
+  if (!room.view || e.target.tagName == "INPUT") { return; }
+  const step = 8;
+  let d = {
+    ArrowLeft: [-step, 0], a: [-step, 0],
+    ArrowRight: [step, 0], d: [step, 0],
+    ArrowUp: [0, -step], w: [0, -step],
+    ArrowDown: [0, step], s: [0, step],
+  }[e.key];
+  if (!d) { return; }
+  e.preventDefault();
+  let v = room.view;
+  room.socket.send(`view ${v.x + d[0]} ${v.y + d[1]} ${v.w} ${v.h}`);
+});
+
+function kick() {
+  let name = prompt("kick who?");
+  if (name == null) { return; }
+  room.cursors.forEach((c, oid) => {
+    if (c.name == name && oid != player.uid) {
+      room.socket.send(`kick ${oid}`);
+    }
+  });
+}
+elem.hostreset.onchange = function() {
+  room.socket.send(`hostreset ${elem.hostreset.checked? "on" : "off"}`);
+}
+elem.settingsform.onsubmit = function() {
+  let kvs = [];
+  for (const [k, v] of new FormData(elem.settingsform)) {
+    kvs.push(`${k}=${v}`);
+  }
+  room.socket.send(`settings ${kvs.join(" ")}`);
+  return false;
+}
+
 function volChanged() {
   let newVol = elem.volslider.value;
   localStorage.setItem("audioVolume", JSON.stringify(newVol));
@@ -316,7 +563,8 @@ volChanged();
 (function sendPos() {
   let qp = window.queued_pos;
   if (qp) {
-    room.socket.send(`pos ${qp[0]} ${qp[1]}`);
+    let wp = toWhole(qp);
+    room.socket.send(`pos ${wp[0]} ${wp[1]}`);
     window.queued_pos = undefined;
   }
   setTimeout(function() {
@@ -0,0 +1,62 @@
+use std::num::NonZeroUsize;
+use criterion::{ criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion };
+use websweeper::minesweeper::{ Board, BoardConf, BoardPos, FlagScope };
+
+// Huge boards: laying one out at a few densities, digging up one with no
+// mines at all from a corner, which floods every tile there is, and reading
+// one whole, as rendering and working out its 3BV do.
+
+const SIDE: usize = 1000;
+
+fn conf(mine_ratio: (usize, usize)) -> BoardConf {
+    BoardConf {
+        w: NonZeroUsize::new(SIDE).unwrap(),
+        h: NonZeroUsize::new(SIDE).unwrap(),
+        mine_ratio: (mine_ratio.0, NonZeroUsize::new(mine_ratio.1).unwrap()),
+        always_safe_first_move: false,
+        revealed_borders: false,
+        reveal_on_lose: false,
+        num_tile_reveal: false,
+        flag_scope: FlagScope::Shared,
+        fog_radius: None,
+        hardcore_lives: None,
+    }
+}
+
+fn generation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("generate 1000x1000");
+    group.sample_size(10);
+    for ratio in [(1, 8), (1, 2), (9, 10)] {
+        let id = BenchmarkId::from_parameter(format!("{}/{}", ratio.0, ratio.1));
+        group.bench_with_input(id, &conf(ratio), |bench, conf| bench.iter(|| Board::new(*conf, 7)));
+    }
+    group.finish();
+}
+
+fn flood(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flood reveal 1000x1000");
+    group.sample_size(10);
+    let corner: BoardPos = (0u32, 0u32).try_into().unwrap();
+    group.bench_function("whole board", |bench| bench.iter_batched(
+        || Board::new(conf((0, 1)), 7),
+        |mut board| {
+            board.reveal(corner, None);
+            assert_eq!(board.hidden_tiles, 0);
+            board
+        },
+        BatchSize::LargeInput,
+    ));
+    group.finish();
+}
+
+fn read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read 1000x1000");
+    group.sample_size(10);
+    let board = Board::new(conf((1, 6)), 7);
+    group.bench_function("render", |bench| bench.iter(|| board.render()));
+    group.bench_function("bbbv", |bench| bench.iter(|| board.bbbv()));
+    group.finish();
+}
+
+criterion_group!(benches, generation, flood, read);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+use std::{ num::NonZeroUsize, sync::Arc };
+use criterion::{ criterion_group, criterion_main, Criterion };
+use warp::ws::Message;
+use websweeper::{ gameloop, minesweeper::{ Board, BoardConf, FlagScope } };
+
+// One board going out to a full room: compressing it for every recipient, as
+// it used to be, against compressing it once and copying the frame out of the
+// shared `Arc` per recipient, which is what `Outbox::recv` still has to do.
+
+const RECIPIENTS: usize = 30;
+/// the default `view_area`, what a single render tops out at
+const SIDE: usize = 150;
+
+fn board() -> Board {
+    let conf = BoardConf {
+        w: NonZeroUsize::new(SIDE).unwrap(),
+        h: NonZeroUsize::new(SIDE).unwrap(),
+        mine_ratio: (1, NonZeroUsize::new(6).unwrap()),
+        always_safe_first_move: false,
+        revealed_borders: false,
+        reveal_on_lose: false,
+        num_tile_reveal: false,
+        flag_scope: FlagScope::Shared,
+        fog_radius: None,
+        hardcore_lives: None,
+    };
+    let mut board = Board::new(conf, 7);
+    // some of it dug up, so it doesn't compress down to nothing
+    for i in (0..SIDE as u32).step_by(9) {
+        board.reveal((i, (i * 7) % SIDE as u32).try_into().unwrap(), None);
+    }
+    board
+}
+
+fn broadcast(c: &mut Criterion) {
+    let rendered = board().render();
+    let mut group = c.benchmark_group(format!("board to {RECIPIENTS}"));
+    group.bench_function("deflate each", |bench| bench.iter(|| {
+        (0..RECIPIENTS).map(|_| gameloop::deflated(&rendered)).collect::<Vec<Message>>()
+    }));
+    group.bench_function("deflate once, copy each", |bench| bench.iter(|| {
+        let frame = Arc::new(gameloop::deflated(&rendered));
+        (0..RECIPIENTS).map(|_| Arc::unwrap_or_clone(frame.clone())).collect::<Vec<Message>>()
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, broadcast);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+use criterion::{ criterion_group, criterion_main, BenchmarkId, Criterion, Throughput };
+use websweeper::livepos;
+
+// One tick's worth of cursor updates for rooms of a few sizes, through both
+// encodings. Sizes on the wire get printed before timing, since that's what
+// the binary frames are for.
+
+fn tick(players: usize) -> Vec<(usize, (u32, u32))> {
+    (0..players)
+        .map(|uid| (uid, ((uid as u32).wrapping_mul(0x9e3779b9), (uid as u32).wrapping_mul(0x85ebca6b))))
+        .collect()
+}
+
+fn json(p: &[(usize, (u32, u32))]) -> Vec<u8> {
+    let json = livepos::jsonenc(p).expect("couldn't JSONify player positions");
+    format!("pos {json}").into_bytes()
+}
+
+fn encodings(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pos tick");
+    for players in [4, 32, 256] {
+        let p = tick(players);
+        let (j, b) = (json(&p).len(), livepos::binenc(&p).len());
+        println!("{players} players: json {j} bytes, binary {b} bytes ({:.0}%)", 100.0 * b as f64 / j as f64);
+        group.throughput(Throughput::Elements(players as u64));
+        group.bench_with_input(BenchmarkId::new("json", players), &p, |bench, p| bench.iter(|| json(p)));
+        group.bench_with_input(BenchmarkId::new("binary", players), &p, |bench, p| bench.iter(|| livepos::binenc(p)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encodings);
+criterion_main!(benches);
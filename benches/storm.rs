@@ -0,0 +1,84 @@
+use std::{ collections::HashMap, net::SocketAddr, num::NonZeroUsize, sync::Arc };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use tokio::time::{ Duration, Instant };
+use websweeper::{ types::*, minesweeper::*, room, gameloop, livepos };
+
+// A 30 player click storm against a co-op room: everyone toggles flags on
+// random tiles as fast as the command rate limit lets them, for a few seconds,
+// once per frame rate cap. What's measured is how many board updates a player
+// ends up receiving a second, against how many clicks went in.
+
+const PLAYERS: usize = 30;
+/// per player, what `rates.commands` lets through in the sample config
+const CLICKS_PER_SEC: u64 = 100;
+const STORM: Duration = Duration::from_secs(3);
+
+fn board() -> BoardConf {
+    let form: HashMap<String, String> = [("bwidth", "100"), ("bheight", "100"), ("mineratio-n", "1"), ("mineratio-d", "8")]
+        .iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    BoardConf::from_form(&form).expect("bad bench board")
+}
+
+/// Board updates a player received per second, and clicks sent per second
+async fn storm(max_fps: u32) -> (f64, f64) {
+    let bconf = board();
+    let conf = RoomConf {
+        name: "storm".into(), mode: GameMode::Coop, player_cap: NonZeroUsize::new(PLAYERS).unwrap(), spectator_cap: 0,
+        public: false, board_conf: bconf, turn_limit: None, view_area: bconf.area(), password: None,
+    };
+    let (irc_tx, mut irc_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (lb_tx, _lb_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move { while irc_rx.recv().await.is_some() {} });
+    let game = Box::new(gameloop::SharedGame::new(irc_tx, lb_tx, conf.clone()));
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let task = room::RoomTask::new(RoomId("storm".into()), conf, PlayerMap::new(false, false), livepos::LivePos::new(0), game, max_fps);
+    let room = tokio::spawn(task.run(rx));
+
+    let updates = Arc::new(AtomicUsize::new(0));
+    let mut conns = vec![];
+    for i in 0..PLAYERS {
+        let addr: SocketAddr = format!("127.0.0.1:{}", 1000 + i).parse().unwrap();
+        let conn = Conn::new(addr, 1 << 16);
+        tx.send(RoomMsg::Join { conn: conn.clone(), name: format!("p{i}"), clr: "#fff".into(), spectate: false }).unwrap();
+        // everyone reads their outbox, only the first one's get counted
+        let (outbox, updates) = (conn.tx.clone(), updates.clone());
+        tokio::spawn(async move {
+            while let Some(m) = outbox.recv().await {
+                let board = m.is_binary() || m.to_str().map(|s| s.starts_with("delta ")).unwrap_or(false);
+                if i == 0 && board { updates.fetch_add(1, Ordering::Relaxed); }
+            }
+        });
+        conns.push(conn);
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    updates.store(0, Ordering::Relaxed);
+
+    let start = Instant::now();
+    let mut clicks = 0;
+    let mut seed: u64 = 0x2545f4914f6cdd1d;
+    // one click at a time, taking turns, so they don't all land in the same batch
+    let mut interv = tokio::time::interval(Duration::from_secs(1) / (CLICKS_PER_SEC as usize * PLAYERS) as u32);
+    for c in conns.iter().cycle() {
+        if start.elapsed() >= STORM { break; }
+        interv.tick().await;
+        seed ^= seed << 13; seed ^= seed >> 7; seed ^= seed << 17;
+        let pos = ((seed % 100) as u32, ((seed >> 32) % 100) as u32);
+        let m = Move { t: MoveType::ToggleFlag, pos: pos.try_into().unwrap() };
+        tx.send(RoomMsg::Cmd(c.addr, Command::Move(m))).unwrap();
+        clicks += 1;
+    }
+    let secs = start.elapsed().as_secs_f64();
+    let received = updates.load(Ordering::Relaxed);
+    room.abort();
+    (received as f64 / secs, clicks as f64 / secs)
+}
+
+fn main() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    println!("{PLAYERS} players, {CLICKS_PER_SEC} clicks/s each, {}s per run", STORM.as_secs());
+    for fps in [0, 60, 30, 15] {
+        let (ups, cps) = rt.block_on(storm(fps));
+        let cap = if fps == 0 { "uncapped".to_string() } else { format!("{fps} fps") };
+        println!("{cap:>9}: {ups:7.1} board updates/s per player for {cps:.0} clicks/s");
+    }
+}
@@ -3,32 +3,70 @@ use std::{
     sync::Arc,
     net::SocketAddr,
 };
-use tokio::sync::RwLock;
+use tokio::time::Duration;
 use futures::{SinkExt, TryStreamExt, StreamExt, stream::SplitStream};
 use warp::ws::{ WebSocket, Message };
 use crate::livepos;
 use crate::ircbot;
+use crate::world;
+use crate::ratelimit::{ RateConf, TokenBucket };
 
-pub async fn setup_conn(socket: WebSocket, addr: SocketAddr, rinfo: (RoomId,Arc<RwLock<Room>>), max_in: usize) {
+const MAX_AUTH_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnConf {
+    pub max_in: usize,
+    pub out_cap: usize,
+    pub board_area: usize,
+    pub view_area: usize,
+    pub ping_interval: Duration,
+    pub idle_timeout: Duration,
+    /// per connection, cursor positions aside
+    pub cmd_rate: RateConf,
+    /// cursor positions, past it they're dropped rather than the connection
+    pub pos_rate: RateConf,
+}
+
+/// Tell a client why it can't come in, then hang up
+pub async fn refuse(socket: WebSocket, why: &str) {
+    let (mut outgoing, _) = socket.split();
+    let _ = outgoing.send(Message::text(format!("err {why}"))).await;
+    let _ = outgoing.close().await;
+}
+
+pub async fn setup_conn(socket: WebSocket, addr: SocketAddr, rinfo: (RoomId,Arc<Room>), cconf: ConnConf) {
     let (room_id, room) = rinfo;
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
     let (mut outgoing, incoming) = socket.split();
-    let conn = Conn { addr, tx };
+    let conn = Conn::new(addr, cconf.out_cap);
 
     println!("{room_id} I: Incoming TCP connection from: {}", addr);
 
-    let full = {
-        let rl = room.read().await;
-        let pcap = rl.conf.player_cap;
-        let pl = rl.players.read().await;
-        pl.len() >= pcap.get()
+    let watchdog = {
+        let conn = conn.clone();
+        let (ping_interval, idle_timeout) = (cconf.ping_interval, cconf.idle_timeout);
+        async move {
+            let mut interv = tokio::time::interval(ping_interval);
+            interv.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interv.tick().await;
+                let idle = conn.idle_for();
+                if idle > idle_timeout {
+                    return idle;
+                }
+                // browsers answer these on their own, so even a frozen tab keeps its seat
+                if conn.tx.send(Message::ping(Vec::new())).is_err() {
+                    return idle;
+                }
+            }
+        }
     };
-    if full { return }
-    let drive_game = drive_conn((conn, incoming), (room_id.clone(),room.clone()), max_in);
+    let drive_game = drive_conn((conn.clone(), incoming), (room_id.clone(),room.clone()), cconf);
     let send_to_client = {
         let room_id = room_id.clone();
+        let outbox = conn.tx.clone();
+        let outgoing = &mut outgoing;
         async move {
-            while let Some(m) = rx.recv().await {
+            while let Some(m) = outbox.recv().await {
                 if let Err(e) = outgoing.send(m).await {
                     println!("{room_id} E: something went bad lol: {e}");
                 }
@@ -38,39 +76,53 @@ pub async fn setup_conn(socket: WebSocket, addr: SocketAddr, rinfo: (RoomId,Arc<
 
     tokio::select! {
         _ = drive_game => (),
-        _ = send_to_client => { println!("{room_id} E: anomalous close for {addr}"); }
+        _ = send_to_client => { println!("{room_id} I: outbound queue closed for {addr}"); }
+        idle = watchdog => { println!("{room_id} I: {addr} timed out after {}s of silence", idle.as_secs()); }
     };
-
-    let room_lock = room.read().await;
-    let mut players = room_lock.players.write().await;
-    if let Some(disconn_p) = players.remove(&addr) {
-        if let Err(e) = room_lock.pos_stream.send(livepos::Req { id: disconn_p.uid, data: livepos::ReqData::Quit }) {
-            println!("{room_id} E: couldn't send removal request for {disconn_p} from the live position system: {e}");
+    conn.tx.close();
+    // let whatever was said last (errors, mostly) reach the client before hanging up
+    let flush = async {
+        while let Some(m) = conn.tx.recv().await {
+            if outgoing.send(m).await.is_err() { break; }
         }
-        for p in players.values() {
-            if let Err(e) = p.conn.tx.send(Message::text(format!("logoff {}", disconn_p.uid))) {
-                println!("{room_id} E: couldn't deliver logoff info to {}: {}", p, e);
-            }
-        }
-        println!("{room_id} I: {disconn_p} disconnected");
-    } else {
-        println!("{room_id} I: {addr} disconnected");
+        let _ = outgoing.close().await;
+    };
+    let _ = tokio::time::timeout(Duration::from_secs(1), flush).await;
+
+    if let Err(e) = room.cmd_stream.send(RoomMsg::Leave(addr)) {
+        println!("{room_id} E: couldn't tell the room {addr} left: {e}");
     }
 }
 
 
-pub async fn drive_conn(conn: (Conn, SplitStream<WebSocket>), rinfo: (RoomId, Arc<RwLock<Room>>), max_in: usize) {
+pub async fn drive_conn(conn: (Conn, SplitStream<WebSocket>), rinfo: (RoomId, Arc<Room>), cconf: ConnConf) {
     let (conn, mut incoming) = conn;
     let (room_id, room) = rinfo;
-    let (players, cmd_tx, pos_tx, irc_tx, room_conf) = {
-        let room = room.read().await;
-        (room.players.clone(), room.cmd_stream.clone(), room.pos_stream.clone(), room.irc_stream.clone(), room.conf.clone())
-    };
+    let (cmd_tx, irc_tx, room_conf) = (room.cmd_stream.clone(), room.irc_stream.clone(), room.conf.clone());
+    let mut authed = room_conf.password.is_none();
+    let mut failed_auths = 0;
+    // once in, the room keeps track of who they are
+    let mut joined = false;
+    let (mut cmd_bucket, mut pos_bucket) = (TokenBucket::new(cconf.cmd_rate), TokenBucket::new(cconf.pos_rate));
     while let Ok(cmd) = incoming.try_next().await {
         if let Some(cmd) = cmd {
+            conn.touch();
+            if cmd.is_ping() || cmd.is_pong() {
+                continue; // liveness only, warp answers pings for us
+            }
+            // the client sends its cursor every frame, so that gets a budget of its own
+            let is_pos = cmd.as_bytes().starts_with(b"pos ");
+            if is_pos && !pos_bucket.take() {
+                continue;
+            }
+            if !is_pos && !cmd_bucket.take() {
+                println!("{room_id} I: {} is sending too fast, dropping them", conn.addr);
+                let _ = conn.tx.send(Message::text("err too many commands, slow down"));
+                return
+            }
             // if it ain't text we can't handle it
             let cmd = match cmd.to_str() {
-                Ok(cmd) => { if cmd.len() > max_in {
+                Ok(cmd) => { if cmd.len() > cconf.max_in {
                     println!("{room_id} E: string too big: {cmd}");
                     return
                 } else { cmd.to_owned() } },
@@ -85,104 +137,101 @@ pub async fn drive_conn(conn: (Conn, SplitStream<WebSocket>), rinfo: (RoomId, Ar
             };
             if let Some(cmd_name) = fields.next() {
                 if cmd_name == "<3" {
-                    continue; // heartbeat, no need to handle it
+                    continue; // heartbeat, already accounted for by touch() above
+                }
+                if cmd_name == "posfmt" {
+                    match fields.next() {
+                        Some("bin") => conn.tx.set_pos_format(livepos::PosFormat::Binary),
+                        Some("json") => conn.tx.set_pos_format(livepos::PosFormat::Json),
+                        f => println!("{room_id} E: unknown position format {f:?} from {}", conn.addr),
+                    }
+                    continue;
                 }
                 use crate::minesweeper::{Move,MoveType};
-                let mut players_lock = players.write().await;
-                match players_lock.get_mut(&conn.addr) {
-                    Some(me) => match cmd_name {
-                        "pos" => {
-                            if let Some(pos) = parse_pos(fields) {
-                                if let Err(e) = pos_tx.send(livepos::Req { id: me.uid, data: livepos::ReqData::Pos(pos) }) {
-                                    println!("{room_id} E: couldn't process {me}'s position update: {e}");
-                                };
-                            }
+                if joined {
+                    let parsed = match cmd_name {
+                        "pos" => parse_pos(fields).map(Command::Pos),
+                        "view" => match parse_view(fields, cconf.view_area) {
+                            Some(view) => Some(Command::View(view)),
+                            None => { reject(&room_id, &conn, "bad view"); continue },
                         },
-                        "reveal" => {
-                            match parse_pos(fields) {
-                                Some(pos) => {
-                                    if let Err(e) = cmd_tx.send(MetaMove::Move(
-                                            Move {t: MoveType::Reveal, pos: pos.try_into().unwrap() }, conn.addr))
-                                    {
-                                        println!("{room_id} E: couldn't process {me}'s reveal command: {e}");
-                                    };
-                                },
-                                None => {
-                                    println!("{room_id} E: bad reveal from {me}");
-                                }
-                            }
+                        "reveal" | "flag" => {
+                            let t = if cmd_name == "reveal" { MoveType::Reveal } else { MoveType::ToggleFlag };
+                            let m = parse_pos(fields).map(|pos| Command::Move(Move { t, pos: pos.try_into().unwrap() }));
+                            if m.is_none() { println!("{room_id} E: bad {cmd_name} from {}", conn.addr); }
+                            m
                         },
-                        "flag" => {
-                            match parse_pos(fields) {
-                                Some(pos) => {
-                                    if let Err(e) = cmd_tx.send(MetaMove::Move(
-                                            Move { t: MoveType::ToggleFlag, pos: pos.try_into().unwrap() }, conn.addr))
-                                    {
-                                        println!("{room_id} E: couldn't process {me}'s flag command: {e}");
-                                    };
-                                },
-                                None => {
-                                    println!("{room_id} E: bad flag from {me}");
-                                }
+                        "reset" => Some(Command::Reset),
+                        "kick" => fields.next().and_then(|uid| uid.parse::<usize>().ok()).map(Command::Kick),
+                        "hostreset" => Some(Command::HostReset(fields.next() == Some("on"))),
+                        "settings" => {
+                            let form = fields
+                                .filter_map(|kv| kv.split_once('='))
+                                .map(|(k,v)| (k.to_owned(), v.to_owned()))
+                                .collect::<std::collections::HashMap<_,_>>();
+                            match crate::minesweeper::BoardConf::from_form(&form) {
+                                Some(bconf) if bconf.area() <= room_conf.mode.max_area(cconf.board_area, cconf.view_area) => Some(Command::Settings(bconf)),
+                                Some(_) => { reject(&room_id, &conn, "board too big"); continue },
+                                None => { reject(&room_id, &conn, "bad settings"); continue },
                             }
                         },
-                        "reset" => {
-                            if let Err(e) = cmd_tx.send(MetaMove::Reset) {
-                                println!("{room_id} E: couldn't request game dump in behalf of {me}: {e}");
-                            }
+                        e => { println!("{room_id} E: unknown command {e:?} from {}: \"{cmd}\"", conn.addr); continue },
+                    };
+                    if let Some(c) = parsed {
+                        if let Err(e) = cmd_tx.send(RoomMsg::Cmd(conn.addr, c)) {
+                            println!("{room_id} E: couldn't pass on {}'s {cmd_name}: {e}", conn.addr);
+                        }
+                    }
+                } else if cmd_name == "auth" {
+                    let attempt = cmd.strip_prefix("auth ").unwrap_or("");
+                    let reply = match &room_conf.password {
+                        Some(pw) if !pw.check(attempt).await => {
+                            failed_auths += 1;
+                            println!("{room_id} I: wrong password from {} ({failed_auths} so far)", conn.addr);
+                            "err wrong password"
                         },
-                        e => println!("{room_id} E: unknown command {e:?} from {me}: \"{cmd}\""),
-                    },
-                    None => {
-                        if cmd_name == "register" {
-                            let mut all_fields = fields.collect::<Vec<&str>>();
-                            let clr = all_fields.pop().expect("register without color").chars().filter(|c| c.is_digit(16) || *c == '#').collect::<String>();
-                            let name = {
-                                let def = "anon".to_string();
-                                if all_fields.is_empty() { def }
-                                else {
-                                    let n = ammonia::clean(&all_fields.join(" "));
-                                    if n.is_empty() { def } else { n }
-                                }
-                            };
-
-                            let (nameq_tx, nameq_rx) = tokio::sync::oneshot::channel();
-                            irc_tx.send(ircbot::IrcCmd::NameTakenQuery(name.clone(), nameq_tx)).expect("couldn't check for name collision");
+                        _ => { authed = true; "authack" },
+                    };
+                    if let Err(e) = conn.tx.send(Message::text(reply)) {
+                        println!("{room_id} E: couldn't answer {}'s auth: {e}", conn.addr);
+                    }
+                    if failed_auths >= MAX_AUTH_ATTEMPTS { return }
+                    if !authed {
+                        if let Err(e) = conn.tx.send(Message::text("pwreq")) {
+                            println!("{room_id} E: couldn't ask {} for the password: {e}", conn.addr);
+                        }
+                    }
+                } else if cmd_name == "register" || cmd_name == "spectate" {
+                    if !authed {
+                        if let Err(e) = conn.tx.send(Message::text("pwreq")) {
+                            println!("{room_id} E: couldn't ask {} for the password: {e}", conn.addr);
+                        }
+                        continue;
+                    }
+                    let mut all_fields = fields.collect::<Vec<&str>>();
+                    let clr = all_fields.pop().expect("register without color").chars().filter(|c| c.is_ascii_hexdigit() || *c == '#').collect::<String>();
+                    let name = {
+                        let def = "anon".to_string();
+                        if all_fields.is_empty() { def }
+                        else {
+                            let n = ammonia::clean(&all_fields.join(" "));
+                            if n.is_empty() { def } else { n }
+                        }
+                    };
 
-                            if nameq_rx.await.unwrap() {
-                                println!("{room_id} I: name collision \"{name}@{}\"", conn.addr);
-                                conn.tx.send(Message::text("namecoll")).expect("couldn't send name collision report");
-                            } else {
-                                println!("{room_id} I: registered \"{name}@{}\"", conn.addr);
-                                drop(players_lock);
-                                let uid = {
-                                    // new scope cuz paranoid bout deadlocks
-                                    room.write().await.players.write().await.insert_conn(conn.clone(), name.clone(), clr)
-                                };
-                                let players_lock = players.read().await;
-                                let me = players_lock.get(&conn.addr).unwrap();
-                                conn.tx.send(Message::text(format!("regack {} {} {} {}",
-                                                                   room_conf.name.replace(' ', "&nbsp;"), name.replace(' ', "&nbsp;"), uid, room_conf.board_conf))
-                                            ).expect("couldn't send register ack");
+                    let (nameq_tx, nameq_rx) = tokio::sync::oneshot::channel();
+                    irc_tx.send(ircbot::IrcCmd::NameTakenQuery(name.clone(), nameq_tx)).expect("couldn't check for name collision");
 
-                                {
-                                    let msg = Message::text(format!("players {}",
-                                                                    jsonenc_players(players_lock.values())
-                                                                    .expect("couldn't JSONify players")));
-                                    for p in players_lock.values() {
-                                        if let Err(e) = p.conn.tx.send(msg.clone()) {
-                                            println!("{room_id} E: couldn't dump players for {me}: {e}");
-                                        }
-                                    }
-                                }
-                                if let Err(e) = pos_tx.send(livepos::Req { id: uid, data: livepos::ReqData::StateDump }) {
-                                    println!("{room_id} E: couldn't request position dump for {me}: {e}");
-                                }
-                                if let Err(e) = cmd_tx.send(MetaMove::StateDump) {
-                                    println!("{room_id} E: couldn't request game dump for {me}: {e}");
-                                }
-                            }
+                    if nameq_rx.await.unwrap() {
+                        println!("{room_id} I: name collision \"{name}@{}\"", conn.addr);
+                        conn.tx.send(Message::text("namecoll")).expect("couldn't send name collision report");
+                    } else {
+                        let spectate = cmd_name == "spectate";
+                        if let Err(e) = cmd_tx.send(RoomMsg::Join { conn: conn.clone(), name, clr, spectate }) {
+                            println!("{room_id} E: couldn't let {} in: {e}", conn.addr);
+                            return
                         }
+                        joined = true;
                     }
                 }
             }
@@ -193,10 +242,17 @@ pub async fn drive_conn(conn: (Conn, SplitStream<WebSocket>), rinfo: (RoomId, Ar
     }
 }
 
-fn jsonenc_players<'a, I: IntoIterator<Item=&'a Player>>(players: I) -> Result<String, serde_json::Error> {
-    let mut pairs = Vec::new();
-    for player in players {
-        pairs.push((player.uid, player.name.replace(' ', "&nbsp"), player.clr.clone()));
+fn reject(room_id: &RoomId, conn: &Conn, why: &str) {
+    if let Err(e) = conn.tx.send(Message::text(format!("err {why}"))) {
+        println!("{room_id} E: couldn't tell {} \"{why}\": {e}", conn.addr);
     }
-    serde_json::to_string(&pairs)
+}
+
+/// `view <x> <y> <w> <h>`, the part of the board or world someone wants to see
+fn parse_view(mut fields: std::str::Split<&str>, max_area: usize) -> Option<world::View> {
+    let x = fields.next()?.parse::<i64>().ok().filter(|x| x.abs() < world::REACH)?;
+    let y = fields.next()?.parse::<i64>().ok().filter(|y| y.abs() < world::REACH)?;
+    let w = fields.next()?.parse::<usize>().ok()?;
+    let h = fields.next()?.parse::<usize>().ok()?;
+    (w > 0 && h > 0 && w.checked_mul(h)? <= max_area).then_some(world::View { x, y, w, h })
 }
@@ -0,0 +1,357 @@
+use std::{
+    collections::{ HashMap, VecDeque },
+    net::SocketAddr,
+    sync::Arc,
+};
+use warp::ws::Message;
+use crate::types::*;
+use crate::{ minesweeper, ircbot, leaderboard, stats, livepos, replay };
+use crate::turns::TurnOrder;
+use crate::outbox::Frame;
+use crate::viewport::{ Region, Render, Viewport, ViewUpdate };
+use crate::room::{ self, Dealing, Mode };
+
+pub fn deflated(rendered: &[u8]) -> Message {
+    use flate2::{ Compression, write::DeflateEncoder };
+    use std::io::Write;
+    let mut board_encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    board_encoder.write_all(rendered).unwrap();
+    Message::binary(board_encoder.finish().unwrap())
+}
+
+/// The rendered board, deflated, as clients expect it
+pub fn board_message(board: &minesweeper::Board) -> Message {
+    deflated(&board.render())
+}
+
+/// Bring `p` up to date with `rendered`, their region of the board
+fn send_view(p: &Player, vp: &mut Viewport, game: &minesweeper::Game, region: Region, rendered: Arc<Render>, livepos: &mut livepos::LivePos) {
+    let update = vp.update(&game.board, &game.board_conf, region, rendered);
+    send_update(p, update, game, region, livepos);
+}
+
+fn send_update(p: &Player, update: ViewUpdate, game: &minesweeper::Game, region: Region, livepos: &mut livepos::LivePos) {
+    let sent = match update {
+        ViewUpdate::Nothing => Ok(()),
+        ViewUpdate::Delta(d) => p.conn.tx.send_delta(d),
+        ViewUpdate::Whole(shape, board) => {
+            // only cursors they can see from here on
+            livepos.view(p.uid, region.normalized(&game.board));
+            shape.into_iter().try_for_each(|m| p.conn.tx.send(m)).and_then(|_| p.conn.tx.send_board(board))
+        },
+    };
+    if let Err(e) = sent {
+        println!("couldn't send board to {p}: {e}");
+    }
+}
+
+/// One board for the whole room: co-op, turns and Minesweeper Flags
+pub struct SharedGame {
+    irc_tx: ircbot::IrcCmdTx,
+    lb_tx: leaderboard::LbCmdTx,
+    room_name: String,
+    mode: GameMode,
+    view_area: usize,
+    turns: Option<TurnOrder>,
+    bconf: minesweeper::BoardConf,
+    next_bconf: Option<minesweeper::BoardConf>,
+    game: minesweeper::Game,
+    final_player_name: Option<String>,
+    scores: stats::Scoreboard,
+    /// what everyone can make out on fogged boards
+    sights: HashMap<SocketAddr, minesweeper::Sight>,
+    /// what everyone's looking at and was last sent
+    viewports: HashMap<SocketAddr, Viewport>,
+    recorder: replay::Recorder,
+    replays: VecDeque<Arc<replay::Replay>>,
+    games_done: usize,
+    /// a move went in since everyone was last told
+    desynced: bool,
+    /// the next game's being dealt, the handle's only here until the room takes it
+    resetting: bool,
+    dealing: Option<Dealing>,
+}
+
+impl SharedGame {
+    pub fn new(irc_tx: ircbot::IrcCmdTx, lb_tx: leaderboard::LbCmdTx, room_conf: RoomConf) -> Self {
+        let turns = match room_conf.mode {
+            GameMode::Turns | GameMode::Flags => Some(TurnOrder::new(room_conf.turn_limit.map(std::time::Duration::from_secs))),
+            _ => None,
+        };
+        SharedGame {
+            irc_tx, lb_tx,
+            room_name: room_conf.name,
+            mode: room_conf.mode,
+            view_area: room_conf.view_area,
+            turns,
+            bconf: room_conf.board_conf,
+            next_bconf: None,
+            game: Self::new_game(room_conf.mode, room_conf.board_conf),
+            final_player_name: None,
+            scores: stats::Scoreboard::default(),
+            sights: HashMap::new(),
+            viewports: HashMap::new(),
+            recorder: replay::Recorder::new(),
+            replays: VecDeque::new(),
+            games_done: 0,
+            desynced: true,
+            resetting: false,
+            dealing: None,
+        }
+    }
+
+    fn new_game(mode: GameMode, bconf: minesweeper::BoardConf) -> minesweeper::Game {
+        if mode == GameMode::Flags { minesweeper::Game::new_hunt(bconf) } else { minesweeper::Game::new(bconf) }
+    }
+
+    fn done(&self) -> bool {
+        use minesweeper::Phase;
+        self.game.phase == Phase::Die || self.game.phase == Phase::Win
+    }
+
+    /// Everyone gets the board as they get to see it, and how it's going
+    fn dump(&mut self, peers: &PlayerMap, livepos: &mut livepos::LivePos) {
+        use minesweeper::*;
+        let done = self.done();
+        let game = &self.game;
+        // one render per flag layer and region, or per player through the fog, spectators get the bare board
+        let mut renders = HashMap::new();
+        let fogged = game.board_conf.fog_radius.is_some();
+        let blind = Sight::default();
+        let sights = &self.sights;
+        let mut board_for = |p: &Player, region: Region| {
+            let owner = if p.spectator { None } else { game.flag_owner(p.actor()) };
+            let sight = (fogged && !p.spectator).then(|| sights.get(&p.conn.addr).unwrap_or(&blind));
+            let key = (owner, sight.map(|_| p.conn.addr), region.x, region.y, region.w, region.h);
+            renders.entry(key).or_insert_with(|| Render::new(game.render_for(owner, sight, region))).clone()
+        };
+        let mut reply: Vec<Frame> = vec![];
+        let lpname = self.final_player_name.as_deref().unwrap_or("unknown player");
+        let outcome = || {
+            let mut o = serde_json::to_value(game.summary()).expect("couldn't JSONify game summary");
+            o["player"] = lpname.into();
+            if game.hunt.is_some() {
+                o["team"] = serde_json::to_value(game.leading_team()).expect("couldn't JSONify team");
+            }
+            o["replay"] = self.games_done.into();
+            if let Some(cause) = game.loss_cause() { o["cause"] = cause.into(); }
+            o.to_string()
+        };
+        match game.phase {
+            Phase::Win => {
+                reply.push(Message::text(format!("win {}", outcome())).into());
+                if let Err(e) = self.irc_tx.send(ircbot::IrcCmd::GameWin(lpname.to_string())) {
+                    println!("couldn't send irc win message: {e}");
+                }
+            },
+            Phase::Die => {
+                reply.push(Message::text(format!("lose {}", outcome())).into());
+                let name = lpname.to_string();
+                let cmd = if game.out_of_lives() { ircbot::IrcCmd::GameOutOfLives(name) } else { ircbot::IrcCmd::GameLose(name) };
+                if let Err(e) = self.irc_tx.send(cmd) {
+                    println!("couldn't send irc lose message: {e}");
+                }
+            },
+            _ => (),
+        }
+        if let Some(lives) = game.lives {
+            reply.push(Message::text(format!("lives {lives}")).into());
+        }
+        if let Some(hunt) = game.hunt {
+            let tally = serde_json::json!({ "A": hunt[0], "B": hunt[1], "mines": game.board.mine_count });
+            reply.push(Message::text(format!("teamscore {tally}")).into());
+        }
+        if done {
+            reply.push(Message::text(format!("score {}", self.scores.to_json().expect("couldn't JSONify scoreboard"))).into());
+        }
+        if let Some(turns) = self.turns.as_mut() {
+            // whoever just joined needs to know whose turn it is too
+            turns.sync(peers);
+            if done { turns.pause(); }
+            reply.push(turns.message().into());
+        }
+        for (addr, p) in peers.iter() {
+            let vp = self.viewports.entry(*addr).or_default();
+            let region = vp.region(&game.board, self.view_area);
+            let rendered = board_for(p, region);
+            send_view(p, vp, game, region, rendered, livepos);
+        }
+        for r in reply {
+            peers.broadcast(r);
+        }
+        self.desynced = false;
+    }
+}
+
+impl Mode for SharedGame {
+    fn handle(&mut self, req: MetaMove, peers: &PlayerMap, livepos: &mut livepos::LivePos) {
+        use minesweeper::*;
+        match req {
+            MetaMove::Move(m, o) => if !self.done() {
+                if let Some(turns) = self.turns.as_ref() {
+                    if !turns.is_turn(&o) {
+                        if let Some(p) = peers.get(&o) {
+                            let _ = p.conn.tx.send(Message::text("err not your turn"));
+                        }
+                        return;
+                    }
+                }
+                let (mover, actor) = match peers.get(&o) {
+                    Some(p) => (Some((p.uid, p.name.clone())), p.actor()),
+                    None => (None, Actor::default()),
+                };
+                // they click on what they were sent, which may be just a part of the board
+                let region = self.viewports.entry(o).or_default().region(&self.game.board, self.view_area);
+                let Some(pos) = region.to_board(m.pos) else { return };
+                let m = Move { pos, ..m };
+                let game = &mut self.game;
+                let outcome = game.act_for(m, actor);
+                self.desynced = true;
+                if game.board_conf.fog_radius.is_some() {
+                    self.sights.entry(o).or_default().saw(&game.board.revealed_log);
+                }
+                if let Some((uid, name)) = &mover {
+                    self.scores.record(*uid, name, &m, &outcome, &game.board, game.flag_owner(actor));
+                    self.recorder.moved(*uid, &m);
+                }
+                let done = self.done();
+                let game = &mut self.game;
+                if done {
+                    self.scores.grade(&game.board);
+                    game.grade();
+                    self.final_player_name = mover.map(|(_, name)| name);
+                    self.games_done += 1;
+                    self.replays.push_back(Arc::new(self.recorder.finish(self.games_done, game)));
+                    if self.replays.len() > replay::KEPT { self.replays.pop_front(); }
+                    if game.phase == Phase::Win && game.hunt.is_none() {
+                        let sum = game.summary();
+                        let entry = leaderboard::Entry {
+                            time: sum.time, bbbv: sum.bbbv, bbbv_per_s: sum.bbbv_per_s, efficiency: sum.efficiency,
+                            players: self.scores.names(), room: self.room_name.clone(), when: leaderboard::now_unix(),
+                        };
+                        let mode = if self.turns.is_some() { "turns" } else { "coop" };
+                        if let Err(e) = self.lb_tx.send(leaderboard::LbCmd::Record(game.board_conf.leaderboard_key(mode), entry)) {
+                            println!("couldn't record win on the leaderboard: {e}");
+                        }
+                    }
+                }
+                if let Some(turns) = self.turns.as_mut() {
+                    // flagging is free, digging ends the turn unless it dug up a mine
+                    let dug = outcome.revealed > 0 || outcome.exploded;
+                    if done {
+                        turns.pause();
+                    } else if outcome.found {
+                        turns.restart_clock();
+                        peers.broadcast(turns.message());
+                    } else if dug {
+                        turns.advance(peers);
+                        peers.broadcast(turns.message());
+                    }
+                }
+            },
+            MetaMove::StateDump => self.dump(peers, livepos),
+            MetaMove::Reset => {
+                if self.done() && !self.resetting {
+                    if let Some(newconf) = self.next_bconf.take() { self.bconf = newconf; }
+                    let (mode, bconf) = (self.mode, self.bconf);
+                    self.dealing = Some(room::deal(move || Self::new_game(mode, bconf)));
+                    self.resetting = true;
+                }
+            },
+            MetaMove::Reconf(newconf) => self.next_bconf = Some(newconf),
+            MetaMove::PlayersChanged => {
+                self.sights.retain(|addr, _| peers.contains_key(addr));
+                self.viewports.retain(|addr, _| peers.contains_key(addr));
+                if let Some(turns) = self.turns.as_mut() {
+                    if turns.sync(peers) {
+                        if self.game.phase == Phase::Die || self.game.phase == Phase::Win { turns.pause(); }
+                        peers.broadcast(turns.message());
+                    }
+                }
+            },
+            MetaMove::View(o, view) => {
+                let vp = self.viewports.entry(o).or_default();
+                vp.view = Some(view);
+                if let Some(p) = peers.get(&o) {
+                    let game = &self.game;
+                    let region = vp.region(&game.board, self.view_area);
+                    let owner = if p.spectator { None } else { game.flag_owner(p.actor()) };
+                    let blind = Sight::default();
+                    let fogged = game.board_conf.fog_radius.is_some() && !p.spectator;
+                    let sight = fogged.then(|| self.sights.get(&o).unwrap_or(&blind));
+                    let rendered = Render::new(game.render_for(owner, sight, region));
+                    send_view(p, vp, game, region, rendered, livepos);
+                }
+            },
+            MetaMove::Cursor(o, pos) => if !self.done() {
+                let Some(p) = peers.get(&o) else { return };
+                self.recorder.cursor(p, pos);
+                let game = &self.game;
+                let Some(radius) = game.board_conf.fog_radius else { return };
+                let sight = self.sights.entry(o).or_default();
+                let mut lit = sight.around_cursor(&game.board, radius);
+                if sight.look_at(pos, &game.board) {
+                    // the fog moved with them, only what it uncovered or covered up changed
+                    lit.extend(sight.around_cursor(&game.board, radius));
+                    let owner = game.flag_owner(p.actor());
+                    let vp = self.viewports.entry(o).or_default();
+                    let region = vp.region(&game.board, self.view_area);
+                    let tiles = lit.into_iter().map(|off| (off, game.render_tile_for(owner, Some(sight), off)));
+                    match vp.patch(&game.board, region, tiles) {
+                        Some(update) => send_update(p, update, game, region, livepos),
+                        None => {
+                            let rendered = Render::new(game.render_for(owner, Some(sight), region));
+                            send_view(p, vp, game, region, rendered, livepos);
+                        },
+                    }
+                }
+            },
+        }
+    }
+
+    fn flush(&mut self, peers: &PlayerMap, livepos: &mut livepos::LivePos) {
+        if self.desynced { self.dump(peers, livepos); }
+    }
+
+    fn deadline(&self) -> Option<tokio::time::Instant> {
+        self.turns.as_ref().and_then(TurnOrder::deadline)
+    }
+
+    fn expire(&mut self, peers: &PlayerMap, _livepos: &mut livepos::LivePos) {
+        // they had their chance
+        if let Some(turns) = self.turns.as_mut() {
+            turns.advance(peers);
+            peers.broadcast(turns.message());
+        }
+    }
+
+    fn wants_cursor(&self, addr: &SocketAddr) -> bool {
+        !self.done() && (self.game.board_conf.fog_radius.is_some() || self.recorder.wants(addr))
+    }
+
+    fn summary(&self) -> minesweeper::GameSummary {
+        self.game.summary()
+    }
+
+    fn replays(&self) -> Vec<Arc<replay::Replay>> {
+        self.replays.iter().cloned().collect()
+    }
+
+    fn take_dealing(&mut self) -> Option<Dealing> {
+        self.dealing.take()
+    }
+
+    fn dealt(&mut self, game: Option<minesweeper::Game>, peers: &PlayerMap, livepos: &mut livepos::LivePos) {
+        self.resetting = false;
+        let Some(game) = game else { return };
+        self.game = game;
+        self.scores = stats::Scoreboard::default();
+        self.recorder = replay::Recorder::new();
+        self.sights.values_mut().for_each(minesweeper::Sight::forget);
+        self.viewports.values_mut().for_each(Viewport::invalidate);
+        if let Some(turns) = self.turns.as_mut() {
+            turns.advance(peers);
+        }
+        self.dump(peers, livepos);
+    }
+}
@@ -9,6 +9,8 @@ pub enum IrcCmd {
     NameTakenQuery(String, tokio::sync::oneshot::Sender<bool>),
     GameWin(String),
     GameLose(String),
+    /// lost on wrong flags in a hardcore game, nobody blew up
+    GameOutOfLives(String),
 }
 
 pub type IrcCmdTx = tokio_mpsc::UnboundedSender<IrcCmd>;
@@ -74,6 +76,12 @@ pub async fn manage_irc_channel(_irc_conf: IrcConf, _room_conf: RoomConf, _game_
                 //     println!("couldn't send irc lose message: {e}");
                 // }
             },
+            IrcCmd::GameOutOfLives(_nick) => {
+                // println!("irc {nick} out of lives");
+                // if let Err(e) = client.send(Command::PRIVMSG(channel_name.clone(), format!("Game over, {nick} ran out of lives."))) {
+                //     println!("couldn't send irc lose message: {e}");
+                // }
+            },
         }
     }
 }
@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{ SystemTime, UNIX_EPOCH },
+};
+use tokio::{
+    sync::{ mpsc as tokio_mpsc, oneshot },
+    time::{ Duration, Instant },
+};
+use serde::{ Serialize, Deserialize };
+
+// slowest times get dropped past this, per board configuration
+const MAX_PER_BOARD: usize = 500;
+// past this many board configurations, the one with the stalest best win goes
+const MAX_BOARDS: usize = 200;
+// wins are written out at most this often, not one file rewrite per win
+const SAVE_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// seconds taken
+    pub time: f64,
+    pub bbbv: usize,
+    pub bbbv_per_s: f64,
+    pub efficiency: f64,
+    /// most helpful first
+    pub players: Vec<String>,
+    pub room: String,
+    /// unix time of the win
+    pub when: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Leaderboard {
+    /// `BoardConf::leaderboard_key` -> entries, fastest first
+    boards: HashMap<String, Vec<Entry>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Query {
+    /// only this board configuration
+    pub board: Option<String>,
+    /// only wins from the last this many seconds
+    pub within: Option<u64>,
+    /// entries per board
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum LbCmd {
+    Record(String, Entry),
+    Query(Query, oneshot::Sender<HashMap<String, Vec<Entry>>>),
+    /// write out whatever's waiting to be saved now, answered once it's on disk
+    Flush(oneshot::Sender<()>),
+}
+
+pub type LbCmdTx = tokio_mpsc::UnboundedSender<LbCmd>;
+
+pub fn now_unix() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub async fn keep_leaderboard(path: PathBuf, mut rx: tokio_mpsc::UnboundedReceiver<LbCmd>) {
+    let mut lb: Leaderboard = match tokio::fs::read_to_string(&path).await {
+        Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+            println!("E: couldn't parse leaderboard at {}, starting over: {e}", path.display());
+            Leaderboard::default()
+        }),
+        Err(_) => Leaderboard::default(),
+    };
+    let mut save_at: Option<Instant> = None;
+    loop {
+        let cmd = tokio::select! {
+            cmd = rx.recv() => cmd,
+            _ = tokio::time::sleep_until(save_at.unwrap_or_else(Instant::now)), if save_at.is_some() => {
+                save_at = None;
+                if let Err(e) = save(&path, &lb).await {
+                    println!("E: couldn't save leaderboard to {}: {e}", path.display());
+                }
+                continue;
+            },
+        };
+        let Some(cmd) = cmd else { break };
+        match cmd {
+            LbCmd::Record(key, entry) => {
+                lb.record(key, entry);
+                save_at.get_or_insert_with(|| Instant::now() + SAVE_DELAY);
+            },
+            LbCmd::Query(q, reply_tx) => {
+                let since = q.within.map(|w| now_unix().saturating_sub(w)).unwrap_or(0);
+                let limit = q.limit.unwrap_or(10);
+                let res = lb.boards.iter()
+                    .filter(|(k, _)| q.board.as_ref().map(|b| b == *k).unwrap_or(true))
+                    .map(|(k, entries)| {
+                        (k.clone(), entries.iter().filter(|e| e.when >= since).take(limit).cloned().collect::<Vec<_>>())
+                    })
+                    .filter(|(_, entries)| !entries.is_empty())
+                    .collect();
+                let _ = reply_tx.send(res);
+            },
+            LbCmd::Flush(done_tx) => {
+                if save_at.take().is_some() {
+                    if let Err(e) = save(&path, &lb).await {
+                        println!("E: couldn't save leaderboard to {}: {e}", path.display());
+                    }
+                }
+                let _ = done_tx.send(());
+            },
+        }
+    }
+    if save_at.is_some() {
+        if let Err(e) = save(&path, &lb).await {
+            println!("E: couldn't save leaderboard to {}: {e}", path.display());
+        }
+    }
+}
+
+impl Leaderboard {
+    fn record(&mut self, key: String, entry: Entry) {
+        if !self.boards.contains_key(&key) && self.boards.len() >= MAX_BOARDS {
+            let stalest = self.boards.iter()
+                .min_by_key(|(_, entries)| entries.iter().map(|e| e.when).max().unwrap_or(0))
+                .map(|(k, _)| k.clone());
+            if let Some(k) = stalest { self.boards.remove(&k); }
+        }
+        let entries = self.boards.entry(key).or_default();
+        let at = entries.partition_point(|e| e.time <= entry.time);
+        entries.insert(at, entry);
+        entries.truncate(MAX_PER_BOARD);
+    }
+}
+
+async fn save(path: &PathBuf, lb: &Leaderboard) -> std::io::Result<()> {
+    // write aside and swap, so a crash mid-write doesn't eat the old one
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec(lb)?).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(time: f64, when: u64) -> Entry {
+        Entry { time, bbbv: 1, bbbv_per_s: 1.0, efficiency: 100.0, players: vec![], room: String::new(), when }
+    }
+
+    #[test]
+    fn entries_stay_fastest_first() {
+        let mut lb = Leaderboard::default();
+        for t in [3.0, 1.0, 2.0] { lb.record("k".into(), entry(t, 0)); }
+        let times = lb.boards["k"].iter().map(|e| e.time).collect::<Vec<_>>();
+        assert_eq!(times, vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn stalest_board_makes_room() {
+        let mut lb = Leaderboard::default();
+        for i in 0..MAX_BOARDS { lb.record(format!("k{i}"), entry(1.0, 100 + i as u64)); }
+        lb.record("new".into(), entry(1.0, 1000));
+        assert_eq!(lb.boards.len(), MAX_BOARDS);
+        assert!(!lb.boards.contains_key("k0"));
+        assert!(lb.boards.contains_key("new"));
+    }
+
+    #[tokio::test]
+    async fn flushing_saves_what_is_waiting() {
+        let path = std::env::temp_dir().join(format!("websweeper-lb-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (tx, rx) = tokio_mpsc::unbounded_channel();
+        let keeper = tokio::spawn(keep_leaderboard(path.clone(), rx));
+        tx.send(LbCmd::Record("k".into(), entry(1.0, 0))).unwrap();
+        let (done_tx, done_rx) = oneshot::channel();
+        tx.send(LbCmd::Flush(done_tx)).unwrap();
+        done_rx.await.unwrap();
+        let saved: Leaderboard = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.boards["k"].len(), 1, "well before the save delay");
+        drop(tx);
+        keeper.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,16 @@
+pub mod types;
+pub mod livepos;
+pub mod conn;
+pub mod minesweeper;
+pub mod ircbot;
+pub mod outbox;
+pub mod ratelimit;
+pub mod stats;
+pub mod leaderboard;
+pub mod gameloop;
+pub mod race;
+pub mod turns;
+pub mod world;
+pub mod viewport;
+pub mod replay;
+pub mod room;
@@ -1,79 +1,194 @@
 use crate::types::*;
-use std::sync::Arc;
-use tokio::sync::mpsc as tokio_mpsc;
-use tokio::sync::{ RwLock, Mutex };
+use crate::outbox::Frame;
 use std::collections::{HashMap,HashSet};
-use tokio::time::{self, Duration};
+use std::sync::{ Arc, OnceLock };
+use tokio::time::{ Instant, Duration };
 use warp::ws::Message;
 
-pub enum ReqData {
-    Pos((u32,u32)),
-    StateDump,
-    Quit,
+// Where everyone's cursor is. Positions come in whenever clients feel like it
+// and go out in batches, at most every `MIN_TICK` and less often the more a
+// room's sending, so a busy room stays within its budget.
+
+// about what one position costs on the wire, `[uid,[x,y]],` in JSON
+const POS_BYTES: usize = 28;
+const MIN_TICK: Duration = Duration::from_millis(16);
+const MAX_TICK: Duration = Duration::from_millis(250);
+
+/// How long to wait before the next update, so a room sends at most `budget` bytes a second
+fn next_tick(sent: usize, budget: usize) -> Duration {
+    if budget == 0 { return MIN_TICK; }
+    Duration::from_secs_f64(sent as f64 / budget as f64).clamp(MIN_TICK, MAX_TICK)
 }
 
-pub struct Req {
-    pub id: usize,
-    pub data: ReqData,
+fn in_view(view: &Option<[u32; 4]>, (x, y): (u32, u32)) -> bool {
+    match view {
+        Some([x0, y0, x1, y1]) => (*x0..*x1).contains(&x) && (*y0..*y1).contains(&y),
+        None => true,
+    }
 }
 
-pub async fn livepos(players: Arc<RwLock<PlayerMap>>, mut recv: tokio_mpsc::UnboundedReceiver<Req>) {
-    let positions = Mutex::new(HashMap::new());
-    let dirty = Mutex::new(HashSet::new());
-    let process_upds = async {
-        while let Some(update) = recv.recv().await {
-            let mut dirty = dirty.lock().await;
-            let mut positions = positions.lock().await;
-            match update.data {
-                ReqData::Pos(p) => {
-                    let old = positions.get(&update.id).unwrap_or(&(0,0));
-                    if p != *old {
-                        dirty.insert(update.id);
-                    }
-                    positions.insert(update.id, p);
-                },
-                ReqData::StateDump => {
-                    dirty.clear();
-                    dirty.extend(positions.keys().copied());
-                },
-                ReqData::Quit => {
-                    positions.remove(&update.id);
-                    dirty.retain(|x| *x != update.id);
-                }
-            }
+#[derive(Debug)]
+pub struct LivePos {
+    positions: HashMap<usize, (u32,u32)>,
+    /// moved since the last update
+    dirty: HashSet<usize>,
+    /// the part of the board each uid is looking at, corners in position coordinates, none for all of it
+    views: HashMap<usize, Option<[u32; 4]>>,
+    budget: usize,
+    /// no update before this
+    next: Instant,
+}
+
+impl LivePos {
+    pub fn new(budget: usize) -> Self {
+        LivePos { positions: HashMap::new(), dirty: HashSet::new(), views: HashMap::new(), budget, next: Instant::now() }
+    }
+    pub fn moved(&mut self, uid: usize, p: (u32,u32)) {
+        if self.positions.insert(uid, p) != Some(p) {
+            self.dirty.insert(uid);
+        }
+    }
+    /// Everyone's position goes out with the next update
+    pub fn dump(&mut self) {
+        self.dirty.extend(self.positions.keys().copied());
+    }
+    pub fn view(&mut self, uid: usize, v: Option<[u32; 4]>) {
+        // whatever they couldn't see before might be in sight now
+        if self.views.insert(uid, v) != Some(v) {
+            self.dump();
         }
-    };
-    let periodic_send = async {
-        let mut interv = tokio::time::interval(Duration::from_millis(16));
-        interv.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
-        loop {
-            interv.tick().await;
-            let mut dirty = dirty.lock().await;
-            if dirty.len() > 0 {
-                let mut positions = positions.lock().await;
-                let msg = jsonenc_ids(&mut positions, &*dirty).expect("couldn't JSONify player positions");
-                dirty.clear();
-                let plock = players.read().await;
-                for player in plock.values() {
-                    if let Err(e) = player.conn.tx.send(Message::text(format!("pos {}", msg))) {
-                        println!("E: couldn't send livepos update to {}: {}", player, e);
-                    }
-                }
+    }
+    pub fn quit(&mut self, uid: usize) {
+        self.positions.remove(&uid);
+        self.dirty.remove(&uid);
+        self.views.remove(&uid);
+    }
+    /// When the next update is due, none while nobody's moved
+    pub fn due(&self) -> Option<Instant> {
+        (!self.dirty.is_empty()).then_some(self.next)
+    }
+    /// Send out what moved
+    pub fn tick(&mut self, players: &PlayerMap) {
+        let upd = self.dirty.drain().map(|id| (id, self.positions[&id])).collect::<Vec<_>>();
+        let mut sent = 0;
+        // everyone who didn't move sees the same thing as everyone else looking at the same part
+        let mut shared: HashMap<Option<[u32; 4]>, Arc<PosBatch>> = HashMap::new();
+        for player in players.values() {
+            // nobody needs their own cursor back, nor ones off their screen
+            let view = self.views.get(&player.uid).copied().flatten();
+            let visible = |me: Option<usize>| upd.iter()
+                .filter(|(id, pos)| Some(*id) != me && in_view(&view, *pos))
+                .copied()
+                .collect::<Vec<_>>();
+            let theirs = if upd.iter().any(|(id, _)| *id == player.uid) {
+                PosBatch::new(visible(Some(player.uid)))
+            } else {
+                shared.entry(view).or_insert_with(|| PosBatch::new(visible(None))).clone()
+            };
+            if theirs.positions.is_empty() { continue; }
+            sent += theirs.positions.len() * POS_BYTES;
+            if let Err(e) = player.conn.tx.send_pos(theirs) {
+                println!("E: couldn't send livepos update to {}: {}", player, e);
             }
         }
-    };
+        self.next = Instant::now() + next_tick(sent, self.budget);
+    }
+}
+
+/// One update's worth of positions, encoded once per format for everyone who gets it
+#[derive(Debug)]
+pub struct PosBatch {
+    pub positions: Vec<(usize, (u32,u32))>,
+    json: OnceLock<Frame>,
+    binary: OnceLock<Frame>,
+}
+
+impl PosBatch {
+    pub fn new(positions: Vec<(usize, (u32,u32))>) -> Arc<Self> {
+        Arc::new(PosBatch { positions, json: OnceLock::new(), binary: OnceLock::new() })
+    }
+    pub fn frame(&self, f: PosFormat) -> Frame {
+        match f {
+            PosFormat::Json => self.json.get_or_init(|| {
+                let json = jsonenc(&self.positions).expect("couldn't JSONify player positions");
+                Arc::new(Message::text(format!("pos {json}")))
+            }),
+            PosFormat::Binary => self.binary.get_or_init(|| Arc::new(Message::binary(binenc(&self.positions)))),
+        }.clone()
+    }
+}
 
-    tokio::select!(
-        _ = process_upds => (),
-        _ = periodic_send => ()
-    );
+/// How a connection wants its positions, `pos <json>` text or binary frames
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PosFormat {
+    #[default]
+    Json,
+    Binary,
 }
 
-fn jsonenc_ids<'a, I: IntoIterator<Item=&'a usize>>(positions: &mut HashMap<usize, (u32,u32)>, ids: I) -> Result<String, serde_json::Error> {
-    let mut pairs = Vec::new();
-    for id in ids {
-        pairs.push((id, positions[id]));
-    };
-    serde_json::to_string(&pairs)
+/// First byte of a binary position frame. Boards are raw deflate, which can't start with it
+pub const BIN_POS_TAG: u8 = 0xFF;
+/// bits kept of each coordinate, still a few steps per pixel on the biggest boards
+pub const POS_BITS: u32 = 16;
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+/// The tag, then for each position its uid as a LEB128 varint and the top `POS_BITS` of x and y, little endian
+pub fn binenc(positions: &[(usize, (u32,u32))]) -> Vec<u8> {
+    let mut out = vec![BIN_POS_TAG];
+    for (id, (x, y)) in positions {
+        put_varint(&mut out, *id as u64);
+        out.extend_from_slice(&((x >> (32 - POS_BITS)) as u16).to_le_bytes());
+        out.extend_from_slice(&((y >> (32 - POS_BITS)) as u16).to_le_bytes());
+    }
+    out
 }
 
+pub fn jsonenc(positions: &[(usize, (u32,u32))]) -> Result<String, serde_json::Error> {
+    serde_json::to_string(positions)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(v: u64) -> Vec<u8> {
+        let mut out = vec![];
+        put_varint(&mut out, v);
+        out
+    }
+
+    #[test]
+    fn varints_are_leb128() {
+        assert_eq!(varint(0), [0x00]);
+        assert_eq!(varint(127), [0x7F]);
+        assert_eq!(varint(128), [0x80, 0x01]);
+        assert_eq!(varint(300), [0xAC, 0x02]);
+        assert_eq!(varint(u64::MAX).len(), 10);
+        assert_eq!(*varint(u64::MAX).last().unwrap(), 0x01);
+    }
+
+    #[test]
+    fn binary_positions_keep_the_top_bits() {
+        let bytes = binenc(&[(5, (0x1234_5678, 0xFFFF_FFFF)), (200, (0, 0x8000_0000))]);
+        assert_eq!(bytes, [
+            BIN_POS_TAG,
+            0x05, 0x34, 0x12, 0xFF, 0xFF,
+            0xC8, 0x01, 0x00, 0x00, 0x00, 0x80,
+        ]);
+    }
+
+    #[test]
+    fn batches_encode_once_per_format() {
+        let batch = PosBatch::new(vec![(1, (0, 0))]);
+        assert!(Arc::ptr_eq(&batch.frame(PosFormat::Binary), &batch.frame(PosFormat::Binary)));
+        assert_eq!(batch.frame(PosFormat::Json).to_str().unwrap(), "pos [[1,[0,0]]]");
+    }
+}
@@ -6,15 +6,10 @@ use std::{
     num::NonZeroUsize,
     path::PathBuf,
 };
-use futures::stream::StreamExt;
 use tokio::sync::RwLock;
 use serde::Deserialize;
 
-mod types;
-mod livepos;
-mod conn;
-mod minesweeper;
-mod ircbot;
+use websweeper::{ types, livepos, conn, minesweeper, ircbot, ratelimit, leaderboard, gameloop, race, world, room };
 use types::*;
 
 const CONF_FILE: &str = "./conf.json";
@@ -26,18 +21,75 @@ struct ConfPaths {
     pub assets: PathBuf,
     pub index_page: PathBuf,
     pub room_page: PathBuf,
+    /// where winning times are kept, created if missing
+    #[serde(default = "default_leaderboard")]
+    pub leaderboard: PathBuf,
 }
 #[derive(Deserialize)]
 struct ConfServer {
     pub listen_on: SocketAddr,
 }
+// Everything that came after the first release has a default, so older conf.json files keep working
 #[derive(Deserialize)]
 struct ConfLimits {
     pub board_area: usize,
+    /// most tiles a client gets sent at once, boards bigger than this get viewed a part at a time
+    #[serde(default = "default_view_area")]
+    pub view_area: usize,
+    /// bytes per second of cursor updates a room may send, cursors slow down past it
+    #[serde(default = "default_livepos_budget")]
+    pub livepos_budget: usize,
+    /// most board updates a room sends per second, moves in between go out together
+    #[serde(default = "default_frame_rate")]
+    pub frame_rate: u32,
+    /// milliseconds a new room's board may take to be laid out before creating it fails
+    #[serde(default = "default_board_gen_ms")]
+    pub board_gen_ms: u64,
     pub room_slots: usize,
     pub form_size: u64,
     pub inbound_packet_size: usize,
+    /// messages a connection can have waiting before it's dropped
+    #[serde(default = "default_outbound_queue_len")]
+    pub outbound_queue_len: usize,
+    /// seconds between server pings
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u64,
+    /// seconds of silence before a connection is considered dead
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    #[serde(default)]
+    pub rates: ConfRates,
 }
+/// Token buckets, each client address gets its own, except for messages, which are counted per connection
+#[derive(Deserialize)]
+#[serde(default)]
+struct ConfRates {
+    pub room_creation: ratelimit::RateConf,
+    pub ws_connect: ratelimit::RateConf,
+    /// every inbound websocket message counts but cursor positions, per connection
+    pub commands: ratelimit::RateConf,
+    /// cursor positions per connection, the client sends up to ~60 a second
+    pub positions: ratelimit::RateConf,
+}
+impl Default for ConfRates {
+    fn default() -> Self {
+        ConfRates {
+            room_creation: ratelimit::RateConf { burst: 3.0, per_sec: 0.05 },
+            ws_connect: ratelimit::RateConf { burst: 10.0, per_sec: 1.0 },
+            commands: ratelimit::RateConf { burst: 200.0, per_sec: 100.0 },
+            positions: ratelimit::RateConf { burst: 120.0, per_sec: 75.0 },
+        }
+    }
+}
+fn default_leaderboard() -> PathBuf { "leaderboard.json".into() }
+fn default_view_area() -> usize { 22500 }
+fn default_livepos_budget() -> usize { 262144 }
+fn default_frame_rate() -> u32 { 30 }
+fn default_board_gen_ms() -> u64 { 2000 }
+fn default_outbound_queue_len() -> usize { 256 }
+fn default_ping_interval() -> u64 { 20 }
+fn default_idle_timeout() -> u64 { 90 }
+
 #[derive(Deserialize)]
 struct Conf {
     pub paths: ConfPaths,
@@ -55,7 +107,9 @@ fn main() -> Result<(), Box<dyn Error>> {
 async fn tokio_main(conf: Conf) -> Result<(), Box<dyn Error>> {
     let conf = Arc::new(conf);
     let rooms = Arc::new(RwLock::new(RoomMap::new()));
-    let public_rooms = Arc::new(RwLock::new(HashMap::new()));
+    let public_rooms = Arc::new(RwLock::new(HashMap::<RoomId, String>::new()));
+    let (lb_tx, lb_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(leaderboard::keep_leaderboard(conf.paths.leaderboard.clone(), lb_rx));
     use warp::*;
 
     let index = path::end().and(fs::file(conf.paths.index_page.clone()));
@@ -68,19 +122,24 @@ async fn tokio_main(conf: Conf) -> Result<(), Box<dyn Error>> {
             let rooms = rooms.clone();
             let pubs = pubs.clone();
             async move {
-                let roomsl = rooms.read().await;
-                let pubsl = pubs.read().await;
-                let rooms_pcount = futures::stream::iter(pubsl.iter())
-                    .then(|(id, _):(&RoomId,_)| {
-                        let roomsl = roomsl.clone();
-                        async move {
-                            let room = roomsl.get(id).unwrap().read().await;
-                            let pcount = room.players.read().await.len();
-                            (id.clone(), (pcount, room.conf.player_cap))
-                        }
-                    })
-                    .collect::<HashMap<RoomId,_>>().await;
-                let resp = (&*pubsl, rooms_pcount);
+                // ask the rooms with the lists let go of, a busy room shouldn't hold up room creation
+                let pubsl = pubs.read().await.clone();
+                let listed = {
+                    let roomsl = rooms.read().await;
+                    pubsl.keys().filter_map(|id| Some((id.clone(), roomsl.get(id)?.clone()))).collect::<Vec<_>>()
+                };
+                let rooms_info = futures::future::join_all(listed.into_iter().map(|(id, room)| async move {
+                    let info = room_info(&room).await;
+                    let pcount = (
+                        info.map(|i| i.players).unwrap_or(0), room.conf.player_cap,
+                        info.map(|i| i.spectators).unwrap_or(0), room.conf.spectator_cap,
+                    );
+                    (id, (pcount, info.map(|i| i.summary)))
+                })).await;
+                let (rooms_pcount, rooms_summary): (HashMap<RoomId,_>, HashMap<RoomId,_>) = rooms_info.into_iter()
+                    .map(|(id, (pcount, summary))| ((id.clone(), pcount), (id, summary)))
+                    .unzip();
+                let resp = (&pubsl, rooms_pcount, rooms_summary);
                 Ok::<_,std::convert::Infallible>(
                     reply::json(&resp)
                 )
@@ -95,68 +154,112 @@ async fn tokio_main(conf: Conf) -> Result<(), Box<dyn Error>> {
             let r = rooms.clone();
             let conf = conf.clone();
             async move {
-                let r = r.read().await;
-                let empty_len = empty_rooms(&r).await.len();
-                let space = conf.limits.room_slots - r.len() + empty_len;
+                let (taken, all) = {
+                    let r = r.read().await;
+                    (r.len(), snapshot(&r))
+                };
+                let empty_len = empty_rooms(all).await.len();
+                let space = conf.limits.room_slots.saturating_sub(taken) + empty_len;
                 Ok::<String, std::convert::Infallible>(space.to_string())
             }
         })
     };
+    let leaderboard = {
+        let lb_tx = lb_tx.clone();
+
+        path!("leaderboard").and(query::<leaderboard::Query>()).and_then(move |q| {
+            let lb_tx = lb_tx.clone();
+            async move {
+                let (res_tx, res_rx) = tokio::sync::oneshot::channel();
+                lb_tx.send(leaderboard::LbCmd::Query(q, res_tx)).map_err(|_| reject())?;
+                let res = res_rx.await.map_err(|_| reject())?;
+                Ok::<_, Rejection>(reply::json(&res))
+            }
+        })
+    };
     let rform_recv = {
         let rooms = rooms.clone();
         let pubs = public_rooms.clone();
         let conf = conf.clone();
+        let lb_tx = lb_tx.clone();
+        let limiter = Arc::new(ratelimit::IpLimiter::new(conf.limits.rates.room_creation));
 
         post().and(path("r")).and(body::content_length_limit(conf.limits.form_size)).and(body::form())
-        .and_then(move |rinfo: HashMap<String, String>| {
+        .and(addr::remote())
+        .and_then(move |rinfo: HashMap<String, String>, saddr: Option<SocketAddr>| {
             let rooms = rooms.clone();
             let pubs = pubs.clone();
             let conf = conf.clone();
+            let limiter = limiter.clone();
+            let lb_tx = lb_tx.clone();
             async move {
-                let slots_available = conf.limits.room_slots - rooms.read().await.len();
-                let empty = empty_rooms(&*rooms.read().await).await;
-                if slots_available < 1 {
-                    if slots_available + empty.len() > 0 {
-                        let mut roomsl = rooms.write().await;
-                        let mut pubsl = pubs.write().await;
-                        remove_room(&mut *roomsl, &mut *pubsl, empty[0].clone());
-                    } else {
-                        return Err(reject::custom(NoRoomSlots));
+                if let Some(saddr) = saddr {
+                    if !limiter.check(saddr.ip()) {
+                        println!("I: {saddr} is creating rooms too fast");
+                        return Err(reject::custom(RateLimited));
                     }
                 }
+                // stretching the password takes a moment, that's no work for the async threads
+                let form = {
+                    let conf = conf.clone();
+                    tokio::task::spawn_blocking(move || room_from_form(&rinfo, &conf)).await.map_err(|_| reject())??
+                };
+                // no dealing a board there's no place for
+                make_way(&rooms, conf.limits.room_slots).await?;
+                let (irc_tx, irc_rx) = tokio::sync::mpsc::unbounded_channel();
+                // dealing a big board takes a while, nobody should wait on the room list for it.
+                // The board's area was checked against the limits above, that's what bounds the work
+                let game = deal(&form.0, irc_tx.clone(), lb_tx, &conf).await?;
 
+                // only now that there's a room to put there does an empty one go, and the
+                // slots may have filled up while this one was being dealt
+                let empty = make_way(&rooms, conf.limits.room_slots).await?;
                 let mut rooms = rooms.write().await;
+                if rooms.len() >= conf.limits.room_slots {
+                    match empty.into_iter().find(|id| rooms.contains_key(id)) {
+                        Some(id) => remove_room(&mut rooms, &mut *pubs.write().await, id),
+                        None => return Err(reject::custom(NoRoomSlots)),
+                    }
+                }
                 let uid = RoomId::new_among(rooms.keys());
 
-                match room_from_form(uid.clone(), &rinfo, &conf) {
-                    Ok((room, public)) => {
-                        if public {
-                            pubs.write().await.insert(uid.clone(), serde_json::to_string(&room.conf).unwrap());
-                            println!("New public room: {:?}", room.conf);
-                        } else {
-                            println!("New private room: {:?}", room.conf);
-                        }
-                        rooms.insert(uid.clone(), Arc::new(RwLock::new(room)));
-
-                        Ok(
-                            hyper::Response::builder()
-                            .status(hyper::StatusCode::SEE_OTHER)
-                            .header(hyper::header::LOCATION, format!("./room/{uid}"))
-                            .body(hyper::Body::empty())
-                            .unwrap()
-                          )
-                    },
-                    Err(e) => Err(e),
+                let (room, public) = open_room(uid.clone(), form, game, (irc_tx, irc_rx), &conf);
+                if public {
+                    pubs.write().await.insert(uid.clone(), serde_json::to_string(&room.conf).unwrap());
+                    println!("New public room: {:?}", room.conf);
+                } else {
+                    println!("New private room: {:?}", room.conf);
                 }
+                rooms.insert(uid.clone(), Arc::new(room));
+
+                Ok(
+                    hyper::Response::builder()
+                    .status(hyper::StatusCode::SEE_OTHER)
+                    .header(hyper::header::LOCATION, format!("./room/{uid}"))
+                    .body(hyper::Body::empty())
+                    .unwrap()
+                  )
             }
         })
     };
     let room = {
         let rooms_ws = rooms.clone();
         let rooms_lobby = rooms.clone();
+        let rooms_replays = rooms.clone();
         let prefix = get().and(path!("room" / String / ..));
-        let max_inbound_packet_size = conf.limits.inbound_packet_size;
+        let cconf = conn::ConnConf {
+            max_in: conf.limits.inbound_packet_size,
+            out_cap: conf.limits.outbound_queue_len,
+            board_area: conf.limits.board_area,
+            view_area: conf.limits.view_area,
+            ping_interval: std::time::Duration::from_secs(conf.limits.ping_interval),
+            idle_timeout: std::time::Duration::from_secs(conf.limits.idle_timeout),
+            cmd_rate: conf.limits.rates.commands,
+            pos_rate: conf.limits.rates.positions,
+        };
         let room_path = conf.paths.room_page.clone();
+        let limiter = Arc::new(ratelimit::IpLimiter::new(conf.limits.rates.ws_connect));
+        let replay_limiter = limiter.clone();
 
         // Fixme: better errors
         prefix.and(path!("ws"))
@@ -164,22 +267,78 @@ async fn tokio_main(conf: Conf) -> Result<(), Box<dyn Error>> {
             .and(addr::remote())
             .and_then(move |id: String, websocket: warp::ws::Ws, saddr: Option<SocketAddr>| {
                 let rooms = rooms_ws.clone();
+                let limiter = limiter.clone();
                 async move {
                     let id = RoomId(id);
+                    let saddr = saddr.expect("socket without address");
                     match rooms.read().await.get(&id).cloned() {
+                        Some(_) if !limiter.check(saddr.ip()) => {
+                            println!("{id} I: {saddr} is connecting too fast");
+                            Ok(websocket.on_upgrade(|socket| {
+                                conn::refuse(socket, "too many connections, slow down")
+                            }).into_response())
+                        },
                         Some(r) => {
-                            println!("{id} I: conn from {saddr:?}");
+                            println!("{id} I: conn from {saddr}");
                             Ok(websocket.on_upgrade(move |socket| {
-                                conn::setup_conn(socket, saddr.expect("socket without address"), (id,r), max_inbound_packet_size)
-                            }))
+                                conn::setup_conn(socket, saddr, (id,r), cconf)
+                            }).into_response())
                         },
                         None => {
-                            println!("I: conn from {saddr:?} into inexistent room {id}");
+                            println!("I: conn from {saddr} into inexistent room {id}");
                             Err(reject())
                         }
                     }
                 }
             })
+            .or(prefix.and(path!("replays" / ..))
+                .and(path::param::<usize>().map(Some).or(any().map(|| None)).unify())
+                .and(path::end())
+                .and(query::<HashMap<String, String>>())
+                .and(addr::remote())
+                .and_then(move |id: String, which: Option<usize>, q: HashMap<String, String>, saddr: Option<SocketAddr>| {
+                    let rooms = rooms_replays.clone();
+                    let limiter = replay_limiter.clone();
+                    async move {
+                        let id = RoomId(id);
+                        let Some(room) = rooms.read().await.get(&id).cloned() else { return Err(reject()) };
+                        // same door as the websocket, and guesses cost the same as connecting
+                        if let Some(pw) = &room.conf.password {
+                            let saddr = saddr.expect("socket without address");
+                            if !limiter.check(saddr.ip()) {
+                                return Ok(reply::with_status("Too many requests, slow down", http::StatusCode::TOO_MANY_REQUESTS).into_response());
+                            }
+                            let right = match q.get("pw") {
+                                Some(attempt) => pw.check(attempt).await,
+                                None => false,
+                            };
+                            if !right {
+                                println!("{id} I: wrong or no password for the replays from {saddr}");
+                                return Ok(reply::with_status("Wrong password", http::StatusCode::FORBIDDEN).into_response());
+                            }
+                        }
+                        let (rp_tx, rp_rx) = tokio::sync::oneshot::channel();
+                        let replays = match room.cmd_stream.send(RoomMsg::Replays(rp_tx)) {
+                            Ok(()) => rp_rx.await.unwrap_or_default(),
+                            Err(_) => vec![],
+                        };
+                        Ok(match which {
+                            // what there is to download
+                            None => reply::json(&replays.iter()
+                                .map(|r| serde_json::json!({ "id": r.id, "bconf": r.bconf, "result": r.result, "length": r.length }))
+                                .collect::<Vec<_>>()).into_response(),
+                            Some(n) => match replays.iter().find(|r| r.id == n) {
+                                Some(r) => reply::with_header(
+                                    reply::json(&**r),
+                                    "content-disposition",
+                                    format!("attachment; filename=\"{id}-{n}.json\""),
+                                ).into_response(),
+                                None => reply::with_status("No such replay", http::StatusCode::NOT_FOUND).into_response(),
+                            },
+                        })
+                    }
+                })
+            )
             .or(prefix.and(path::end())
                 .and(fs::file(room_path))
                 .then(move |id: String, f: fs::File| {
@@ -200,6 +359,7 @@ async fn tokio_main(conf: Conf) -> Result<(), Box<dyn Error>> {
         .and(index)
         .or(listing)
         .or(roomspace)
+        .or(leaderboard)
         .or(rform_recv)
         .or(room)
         .or(assets)
@@ -211,76 +371,31 @@ async fn tokio_main(conf: Conf) -> Result<(), Box<dyn Error>> {
         .key_path(conf.paths.pkey.clone())
         .run(conf.server.listen_on);
     println!("Serving on {}", conf.server.listen_on);
-    server.await;
+    tokio::select! {
+        _ = server => (),
+        _ = shutdown_signal() => println!("I: shutting down"),
+    }
+    // the last few seconds' records are still waiting on the save delay
+    let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+    if lb_tx.send(leaderboard::LbCmd::Flush(done_tx)).is_ok() {
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(5), done_rx).await;
+    }
     Ok(())
 }
 
-// If a move is made, broadcast new board, else just send current board
-type MoveStreamHandles = (tokio::sync::mpsc::UnboundedSender<MetaMove>, tokio::sync::mpsc::UnboundedReceiver<MetaMove>);
-async fn gameloop(moves: MoveStreamHandles, irc_tx: ircbot::IrcCmdTx, players: Arc<RwLock<PlayerMap>>, bconf: minesweeper::BoardConf) {
-    // FIXME: push new board if and only if there aren't any remaining commands in the queue
-    use minesweeper::*;
-    use flate2::{ Compression, write::DeflateEncoder };
-    use std::io::Write;
-    let (move_tx, mut move_rx) = moves;
-    let mut game = Game::new(bconf);
-    let mut final_player_name = None;
-    let mut desynced = true;
-    while let Some(req) = move_rx.recv().await {
-        let done = |p: &Phase| { *p == Phase::Die || *p == Phase::Win };
-        match req {
-            MetaMove::Move(m, o) => if !done(&game.phase) {
-                game.act(m);
-                desynced = true;
-                if done(&game.phase) {
-                    game.board.grade();
-                    final_player_name = players.read().await.get(&o).map(|p| p.name.clone());
-                }
-                move_tx.send(MetaMove::StateSync).unwrap();
-            },
-            MetaMove::StateSync => { // a StateDump, but consecutive ones in the queue get merged
-                if desynced { move_tx.send(MetaMove::StateDump).unwrap(); desynced = false; }
-            },
-            MetaMove::StateDump => {
-                use warp::ws::Message;
-                let mut board_encoder = DeflateEncoder::new(Vec::new(), Compression::default());
-                board_encoder.write_all(&game.board.render()).unwrap();
-                let compressed_board = board_encoder.finish().unwrap();
-                let mut reply = vec![Message::binary(compressed_board)];
-                let lpname = final_player_name.as_deref().unwrap_or("unknown player");
-                match game.phase {
-                    Phase::Win => {
-                        reply.push(Message::text(format!("win {lpname}")));
-                        if let Err(e) = irc_tx.send(ircbot::IrcCmd::GameWin(lpname.to_string())) {
-                            println!("couldn't send irc win message: {e}");
-                        }
-                    },
-                    Phase::Die => {
-                        reply.push(Message::text(format!("lose {lpname}")));
-                        if let Err(e) = irc_tx.send(ircbot::IrcCmd::GameLose(lpname.to_string())) {
-                            println!("couldn't send irc lose message: {e}");
-                        }
-                    },
-                    _ => (),
-                }
-                let peers = players.read().await;
-                for (addr, p) in peers.iter() {
-                    for r in reply.iter() {
-                        if let Err(e) = p.conn.tx.send(r.clone()) {
-                            println!("couldn't send game update {r:?} to {addr}: {e}");
-                        }
-                    }
-                }
-                desynced = false;
-            },
-            MetaMove::Reset => {
-                if done(&game.phase) {
-                    game = Game::new(bconf);
-                    move_tx.send(MetaMove::StateDump).unwrap();
-                }
-            },
+/// Ctrl-C, or a service manager asking nicely
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{ signal, SignalKind };
+        let Ok(mut term) = signal(SignalKind::terminate()) else { return tokio::signal::ctrl_c().await.unwrap_or(()) };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = term.recv() => (),
         }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap_or(())
 }
 
 use warp::{ reject::{ Reject, Rejection }, reply::{ self, Reply }, http::StatusCode };
@@ -292,90 +407,150 @@ impl Reject for BadFormData {}
 struct BoardTooBig;
 impl Reject for BoardTooBig {}
 
+#[derive(Debug)]
+struct BoardGenFailed;
+impl Reject for BoardGenFailed {}
+
 #[derive(Debug)]
 struct NoRoomSlots;
 impl Reject for NoRoomSlots {}
 
+#[derive(Debug)]
+struct RateLimited;
+impl Reject for RateLimited {}
+
 async fn error_handler(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
     if err.is_not_found() { Ok(reply::with_status("No such file", StatusCode::NOT_FOUND)) }
     else if let Some(_e) = err.find::<BadFormData>() {
         Ok(reply::with_status("Bad form data", StatusCode::BAD_REQUEST))
     } else if let Some(_e) = err.find::<BoardTooBig>() {
         Ok(reply::with_status("Board too big", StatusCode::BAD_REQUEST))
+    } else if let Some(_e) = err.find::<BoardGenFailed>() {
+        Ok(reply::with_status("Couldn't make the board in time, try a smaller one", StatusCode::SERVICE_UNAVAILABLE))
     } else if let Some(_e) = err.find::<NoRoomSlots>() {
         Ok(reply::with_status("No more rooms slots", StatusCode::BAD_REQUEST))
+    } else if let Some(_e) = err.find::<RateLimited>() {
+        Ok(reply::with_status("Too many requests, slow down", StatusCode::TOO_MANY_REQUESTS))
     } else {
         println!("unhandled rejection: {err:?}");
         Ok(reply::with_status("Server error", StatusCode::INTERNAL_SERVER_ERROR))
     }
 }
 
-async fn empty_rooms(rooms: &RoomMap) -> Vec<RoomId> {
-    futures::stream::iter(rooms.iter())
-        .filter_map(|(id,roomarc)| async move {
-            let rrl = roomarc.read().await;
-            let rrrl = rrl.players.read().await;
-            if rrrl.len() == 0 { Some(id.clone()) } else { None }
-        })
-        .collect::<Vec<RoomId>>().await
+// how long a room gets to answer for the listings before it's left out
+const ROOM_INFO_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Head count and game summary, straight from the room, none if it's gone quiet or is too busy to say
+async fn room_info(room: &Room) -> Option<RoomInfo> {
+    let (info_tx, info_rx) = tokio::sync::oneshot::channel();
+    room.cmd_stream.send(RoomMsg::Info(info_tx)).ok()?;
+    tokio::time::timeout(ROOM_INFO_TIMEOUT, info_rx).await.ok()?.ok()
 }
 
-fn room_from_form(uid: RoomId, rinfo: &HashMap<String,String>, conf: &Conf) -> Result<(types::Room, bool), Rejection> {
-    if let (Some(w),Some(h),Some(num),Some(denom),public,asfm,rborders,revealol,ntiler,Some(limit)) = (
-        rinfo.get("bwidth").and_then(|w| w.parse::<NonZeroUsize>().ok()),
-        rinfo.get("bheight").and_then(|h| h.parse::<NonZeroUsize>().ok()),
-        rinfo.get("mineratio-n").and_then(|n| n.parse::<usize>().ok()),
-        rinfo.get("mineratio-d").and_then(|d| d.parse::<NonZeroUsize>().ok()),
+/// The rooms as they are now, to be asked about without holding the map
+fn snapshot(rooms: &RoomMap) -> Vec<(RoomId, Arc<Room>)> {
+    rooms.iter().map(|(id, room)| (id.clone(), room.clone())).collect()
+}
+
+/// Rooms nobody's in, asked all at once. One that's too busy to answer isn't empty
+async fn empty_rooms(rooms: Vec<(RoomId, Arc<Room>)>) -> Vec<RoomId> {
+    futures::future::join_all(rooms.into_iter().map(|(id, room)| async move {
+        let empty = match room_info(&room).await {
+            Some(i) => i.players + i.spectators == 0,
+            None => room.cmd_stream.is_closed(),
+        };
+        empty.then_some(id)
+    })).await.into_iter().flatten().collect()
+}
+
+/// Empty rooms that could go to make way for a new one, none if there's a free slot anyway
+async fn make_way(rooms: &RwLock<RoomMap>, slots: usize) -> Result<Vec<RoomId>, Rejection> {
+    if rooms.read().await.len() < slots { return Ok(vec![]); }
+    let empty = empty_rooms(snapshot(&*rooms.read().await)).await;
+    if empty.is_empty() { Err(warp::reject::custom(NoRoomSlots)) } else { Ok(empty) }
+}
+
+/// What the room creation form asks for: the room, its players' ground rules and whether it's listed
+fn room_from_form(rinfo: &HashMap<String,String>, conf: &Conf) -> Result<(RoomConf, PlayerMap, bool), Rejection> {
+    if let (Some(board_conf),Some(mode),public,Some(limit)) = (
+        minesweeper::BoardConf::from_form(rinfo),
+        GameMode::from_form(rinfo.get("mode")),
         rinfo.get("public").map(|s| s == "on").unwrap_or(false),
-        rinfo.get("allsafe1move").map(|s| s == "on").unwrap_or(false),
-        rinfo.get("rborders").map(|s| s == "on").unwrap_or(false),
-        rinfo.get("revealonlose").map(|s| s == "on").unwrap_or(false),
-        rinfo.get("numtilereveal").map(|s| s == "on").unwrap_or(false),
         rinfo.get("limit").and_then(|l| l.parse::<NonZeroUsize>().ok()),
         ) {
-        if w.get()*h.get() > conf.limits.board_area {
+        let spectator_cap = rinfo.get("speclimit").and_then(|l| l.parse::<usize>().ok()).unwrap_or(0);
+        let password = rinfo.get("password").filter(|p| !p.is_empty()).map(|p| RoomPassword::new(p));
+        let host_only_reset = rinfo.get("hostreset").map(|s| s == "on").unwrap_or(false);
+        let turn_limit = rinfo.get("turnlimit").and_then(|l| l.parse::<u64>().ok()).filter(|l| *l > 0);
+        if board_conf.area() > mode.max_area(conf.limits.board_area, conf.limits.view_area) {
             return Err(warp::reject::custom(BoardTooBig))
         }
-        let board_conf = minesweeper::BoardConf {
-            w, h, mine_ratio: (num,denom),
-            always_safe_first_move: asfm, revealed_borders: rborders,
-            reveal_on_lose: revealol, num_tile_reveal: ntiler,
-        };
-        let name = {
-            let n = rinfo.get("rname").unwrap().to_owned();
-            if n.is_empty() { uid.to_string() } else { n }
-        };
+        let name = rinfo.get("rname").filter(|n| !n.is_empty()).cloned().unwrap_or_default();
 
-        let players = Arc::new(RwLock::new(PlayerMap::default()));
+        let players = PlayerMap::new(host_only_reset, mode == GameMode::Flags || board_conf.flag_scope == minesweeper::FlagScope::Team);
 
         let room_conf = RoomConf {
             name,
+            mode,
             player_cap: limit,
+            spectator_cap,
             public,
             board_conf,
+            turn_limit,
+            view_area: conf.limits.view_area,
+            password,
         };
-
-        let (cmd_tx, cmd_rx) = tokio::sync::mpsc::unbounded_channel();
-        let (irc_tx, irc_rx) = tokio::sync::mpsc::unbounded_channel();
-        let (pos_tx, pos_rx) = tokio::sync::mpsc::unbounded_channel();
-
-        let irc_handle = tokio::spawn(ircbot::manage_irc_channel(conf.irc.clone(), room_conf.clone(), cmd_tx.clone(), irc_rx));
-        let game_handle = tokio::spawn(gameloop((cmd_tx.clone(), cmd_rx), irc_tx.clone(), players.clone(), board_conf));
-        let livepos_handle = tokio::spawn(livepos::livepos(players.clone(), pos_rx));
-
-        Ok((Room {
-            conf: room_conf,
-            players,
-            game_driver: game_handle,
-            cmd_stream: cmd_tx,
-            livepos_driver: livepos_handle,
-            pos_stream: pos_tx,
-            irc_driver: irc_handle,
-            irc_stream: irc_tx,
-        }, public))
+        Ok((room_conf, players, public))
     } else { Err(warp::reject::custom(BadFormData)) }
 }
 
+/// Set up the room's game on the blocking pool, where laying out a big board holds nobody up.
+/// Past the time budget the room isn't made and the board gets thrown away once it's done:
+/// the budget bounds the wait, the area limit bounds the work.
+async fn deal(room_conf: &RoomConf, irc_tx: ircbot::IrcCmdTx, lb_tx: leaderboard::LbCmdTx, conf: &Conf) -> Result<Box<dyn room::Mode>, Rejection> {
+    let rc = room_conf.clone();
+    let work = tokio::task::spawn_blocking(move || -> Box<dyn room::Mode> {
+        match rc.mode {
+            GameMode::Race => Box::new(race::Race::new(irc_tx, lb_tx, rc)),
+            GameMode::Endless => Box::new(world::Endless::new(rc)),
+            _ => Box::new(gameloop::SharedGame::new(irc_tx, lb_tx, rc)),
+        }
+    });
+    let budget = std::time::Duration::from_millis(conf.limits.board_gen_ms);
+    match tokio::time::timeout(budget, work).await {
+        Ok(Ok(game)) => Ok(game),
+        Ok(Err(e)) => {
+            println!("E: dealing a {} board failed: {e}", room_conf.board_conf);
+            Err(warp::reject::custom(BoardGenFailed))
+        },
+        Err(_) => {
+            println!("W: gave up on dealing a {} board after {budget:?}, it'll be thrown away", room_conf.board_conf);
+            Err(warp::reject::custom(BoardGenFailed))
+        },
+    }
+}
+
+/// Get the room going, under `uid`
+fn open_room(uid: RoomId, form: (RoomConf, PlayerMap, bool), game: Box<dyn room::Mode>, irc: (ircbot::IrcCmdTx, tokio::sync::mpsc::UnboundedReceiver<ircbot::IrcCmd>), conf: &Conf) -> (types::Room, bool) {
+    let (mut room_conf, players, public) = form;
+    if room_conf.name.is_empty() { room_conf.name = uid.to_string(); }
+    let (irc_tx, irc_rx) = irc;
+    let (cmd_tx, cmd_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let irc_handle = tokio::spawn(ircbot::manage_irc_channel(conf.irc.clone(), room_conf.clone(), cmd_tx.clone(), irc_rx));
+    let livepos = livepos::LivePos::new(conf.limits.livepos_budget);
+    let task = room::RoomTask::new(uid, room_conf.clone(), players, livepos, game, conf.limits.frame_rate);
+    let handle = tokio::spawn(task.run(cmd_rx));
+
+    (Room {
+        conf: room_conf,
+        cmd_stream: cmd_tx,
+        driver: handle,
+        irc_driver: irc_handle,
+        irc_stream: irc_tx,
+    }, public)
+}
+
 fn remove_room<T>(rooms: &mut RoomMap, pubs: &mut HashMap<RoomId,T>, id: RoomId) {
     rooms.remove(&id);
     pubs.remove(&id);
@@ -1,9 +1,12 @@
 use std::{
+    collections::{ HashMap, HashSet },
     convert::TryInto,
     num::NonZeroUsize,
 };
-use rand::{ thread_rng, Rng, distributions::Uniform };
+use std::time::Instant;
+use rand::{ Rng, SeedableRng, rngs::StdRng };
 use serde::Serialize;
+use crate::viewport::Region;
 
 const HIDDEN_BIT: u8 = 1 << 7;
 pub const FLAGGED_BIT: u8 = 1 << 6;
@@ -13,8 +16,11 @@ const NUMBITS: u8 = !(HIDDEN_BIT | FLAGGED_BIT | SPECIAL_BIT);
 const MINED: u8 = HIDDEN_BIT | NUMBITS;
 const QUESTION: u8 = FLAGGED_BIT | SPECIAL_BIT;
 const CORRECT: u8 = MINED | SPECIAL_BIT;
+// mines dug up in Minesweeper Flags, by team
+const FOUND_A: u8 = NUMBITS | SPECIAL_BIT;
+const FOUND_B: u8 = NUMBITS | FLAGGED_BIT;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
 pub enum Phase {
     SafeFirstMove,
     FirstMoveFail,
@@ -23,10 +29,157 @@ pub enum Phase {
     Win,
 //    Leave,
 }
+
+/// Sides in Minesweeper Flags
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Serialize)]
+pub enum Team {
+    A,
+    B,
+}
+impl Team {
+    pub fn other(self) -> Team {
+        match self { Team::A => Team::B, Team::B => Team::A }
+    }
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Who a move is made by, for the rules that care
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Actor {
+    pub uid: Option<usize>,
+    pub team: Option<Team>,
+}
+
+/// Who gets to see a flag
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum FlagScope {
+    /// everyone, flags live on the board itself
+    Shared,
+    /// the team that planted it, or just the player when there are no teams
+    Team,
+    /// whoever planted it
+    Player,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FlagOwner {
+    Team(Team),
+    Player(usize),
+}
+
+/// What a player can make out on a fogged board: whatever's near their
+/// cursor, plus what they dug up themselves
+#[derive(Debug, Default, Clone)]
+pub struct Sight {
+    /// tile under the cursor
+    cursor: Option<(usize, usize)>,
+    /// board offsets
+    seen: HashSet<usize>,
+}
+
+impl Sight {
+    /// Move the cursor to `pos`, in livepos' board-relative coordinates.
+    /// True if it landed on a different tile.
+    pub fn look_at(&mut self, pos: (u32, u32), board: &Board) -> bool {
+        let scale = |v: u32, n: NonZeroUsize| ((v as u64 * n.get() as u64) >> 32) as usize;
+        let tile = Some((scale(pos.0, board.width), scale(pos.1, board.height)));
+        let moved = tile != self.cursor;
+        self.cursor = tile;
+        moved
+    }
+    pub fn saw(&mut self, offs: &[usize]) {
+        self.seen.extend(offs);
+    }
+    /// New game, nothing dug up yet
+    pub fn forget(&mut self) {
+        self.seen.clear();
+    }
+    /// Offsets of the tiles within `radius` of the cursor, whether they're revealed or not
+    pub fn around_cursor(&self, board: &Board, radius: usize) -> Vec<usize> {
+        let Some((cx, cy)) = self.cursor else { return vec![] };
+        let (w, h) = (board.width.get(), board.height.get());
+        let (xs, ys) = (cx.saturating_sub(radius)..(cx + radius + 1).min(w), cy.saturating_sub(radius)..(cy + radius + 1).min(h));
+        ys.flat_map(|y| xs.clone().map(move |x| (x, y)))
+            .filter(|(x, y)| x.abs_diff(cx).pow(2) + y.abs_diff(cy).pow(2) <= radius * radius)
+            .map(|(x, y)| x + y * w)
+            .collect()
+    }
+    pub fn sees(&self, board: &Board, radius: usize, off: usize) -> bool {
+        if self.seen.contains(&off) { return true; }
+        let w = board.width.get();
+        let (x, y) = (off % w, off / w);
+        self.cursor.map(|(cx, cy)| {
+            let (dx, dy) = (x.abs_diff(cx), y.abs_diff(cy));
+            dx * dx + dy * dy <= radius * radius
+        }).unwrap_or(false)
+    }
+}
+
+/// Flags kept off the board, one layer per team or player
+#[derive(Debug, Default, Clone)]
+pub struct FlagLayer {
+    /// board offset -> flag bits, as they'd sit on the tile
+    marks: HashMap<usize, u8>,
+}
+
+impl FlagLayer {
+    fn get(&self, off: usize) -> u8 {
+        self.marks.get(&off).copied().unwrap_or(0)
+    }
+    /// Same cycle as `Board::flag`: flag, question, nothing
+    fn cycle(&mut self, off: usize) {
+        match self.get(off) {
+            FLAGGED_BIT => { self.marks.insert(off, QUESTION); },
+            QUESTION => { self.marks.remove(&off); },
+            _ => { self.marks.insert(off, FLAGGED_BIT); },
+        }
+    }
+    /// A flag known to be right, it won't come off
+    fn lock(&mut self, off: usize) {
+        self.marks.insert(off, SPECIAL_BIT);
+    }
+    /// Like `Board::grade`, rightly flagged mines turn CORRECT
+    fn grade(&mut self, board: &Board) {
+        for (off, m) in self.marks.iter_mut() {
+            if *m == FLAGGED_BIT && board.is_mine_at(*off) { *m = SPECIAL_BIT; }
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Game {
     pub phase: Phase,
     pub board: Board,
     pub board_conf: BoardConf,
+    /// first move
+    pub started: Option<Instant>,
+    /// winning or losing move
+    pub ended: Option<Instant>,
+    /// minimum clicks needed to clear the board, see `Board::bbbv`
+    pub bbbv: usize,
+    /// moves that changed anything
+    pub clicks: usize,
+    /// mines found per team, for Minesweeper Flags games where mines score instead of killing
+    pub hunt: Option<[usize; 2]>,
+    /// flags that aren't on the board, see `FlagScope`
+    pub layers: HashMap<FlagOwner, FlagLayer>,
+    /// wrong flags left before it's over, for hardcore games
+    pub lives: Option<usize>,
+}
+
+/// How a game's going or went, in numbers
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct GameSummary {
+    pub phase: Phase,
+    /// seconds since the first move, until the last one if it's over
+    pub time: f64,
+    pub bbbv: usize,
+    pub bbbv_per_s: f64,
+    pub clicks: usize,
+    /// 3BV over effective clicks, as a percentage
+    pub efficiency: f64,
 }
 
 #[derive(Debug, Clone, Copy, Serialize)]
@@ -39,6 +192,59 @@ pub struct BoardConf {
     pub revealed_borders: bool,
     pub reveal_on_lose: bool,
     pub num_tile_reveal: bool,
+    pub flag_scope: FlagScope,
+    /// how far from their cursor players can see revealed tiles, no fog if none
+    pub fog_radius: Option<usize>,
+    /// hardcore: how many wrong flags it takes to lose, right flags lock in place
+    pub hardcore_lives: Option<usize>,
+}
+
+impl BoardConf {
+    /// Read a board configuration out of the room creation form fields
+    pub fn from_form(rinfo: &HashMap<String,String>) -> Option<Self> {
+        let flag = |k: &str| rinfo.get(k).map(|s| s == "on").unwrap_or(false);
+        let w = rinfo.get("bwidth").and_then(|w| w.parse::<NonZeroUsize>().ok())?;
+        let h = rinfo.get("bheight").and_then(|h| h.parse::<NonZeroUsize>().ok())?;
+        let num = rinfo.get("mineratio-n").and_then(|n| n.parse::<usize>().ok())?;
+        let denom = rinfo.get("mineratio-d").and_then(|d| d.parse::<NonZeroUsize>().ok())?;
+        Some(BoardConf {
+            w, h, mine_ratio: (num,denom),
+            always_safe_first_move: flag("allsafe1move"),
+            revealed_borders: flag("rborders"),
+            reveal_on_lose: flag("revealonlose"),
+            num_tile_reveal: flag("numtilereveal"),
+            flag_scope: match rinfo.get("flagscope").map(|s| s.as_str()) {
+                None | Some("shared") => FlagScope::Shared,
+                Some("team") => FlagScope::Team,
+                Some("player") => FlagScope::Player,
+                Some(_) => return None,
+            },
+            fog_radius: rinfo.get("fogradius").and_then(|r| r.parse::<usize>().ok()).filter(|r| *r > 0),
+            hardcore_lives: rinfo.get("hardcore").and_then(|l| l.parse::<usize>().ok()).filter(|l| *l > 0),
+        })
+    }
+    pub fn area(&self) -> usize {
+        self.w.get() * self.h.get()
+    }
+    /// Boards that play the same share a key, however their ratio was written down.
+    /// Every setting that makes a game harder or easier is in it, after the `mode` it was won in
+    pub fn leaderboard_key(&self, mode: &str) -> String {
+        fn gcd(a: usize, b: usize) -> usize { if b == 0 { a } else { gcd(b, a % b) } }
+        let (n, d) = (self.mine_ratio.0, self.mine_ratio.1.get());
+        let g = gcd(n, d).max(1);
+        let mut flags = String::new();
+        if self.always_safe_first_move { flags.push('s'); }
+        if self.revealed_borders { flags.push('b'); }
+        if self.num_tile_reveal { flags.push('n'); }
+        match self.flag_scope {
+            FlagScope::Shared => (),
+            FlagScope::Team => flags.push('t'),
+            FlagScope::Player => flags.push('p'),
+        }
+        if let Some(r) = self.fog_radius { flags.push_str(&format!("f{r}")); }
+        if let Some(l) = self.hardcore_lives { flags.push_str(&format!("h{l}")); }
+        format!("{mode}_{}x{}_{}-{}_{flags}", self.w, self.h, n/g, d/g)
+    }
 }
 
 impl std::fmt::Display for BoardConf {
@@ -47,35 +253,217 @@ impl std::fmt::Display for BoardConf {
     }
 }
 
+// How tiles are kept: four bits apiece, two to a byte. Numbers are kept apart
+// the same way, and follow the mines around as they're laid or moved.
+const T_MINE: u8 = 1;
+const T_HIDDEN: u8 = 1 << 1;
+const T_FLAGGED: u8 = 1 << 2;
+const T_SPECIAL: u8 = 1 << 3;
+
+#[derive(Debug, Clone)]
+struct Tiles {
+    nibbles: Vec<u8>,
+}
+
+impl Tiles {
+    fn new(area: usize, fill: u8) -> Self {
+        Tiles { nibbles: vec![fill | fill << 4; area.div_ceil(2)] }
+    }
+    fn get(&self, off: usize) -> u8 {
+        (self.nibbles[off / 2] >> (off % 2 * 4)) & 0xF
+    }
+    fn set(&mut self, off: usize, t: u8) {
+        let shift = off % 2 * 4;
+        let b = &mut self.nibbles[off / 2];
+        *b = (*b & !(0xF << shift)) | (t << shift);
+    }
+    /// A tile's flag bits, as they sit on a tile byte
+    fn to_bits(t: u8) -> u8 {
+        let mut v = 0;
+        if t & T_HIDDEN > 0 { v |= HIDDEN_BIT; }
+        if t & T_FLAGGED > 0 { v |= FLAGGED_BIT; }
+        if t & T_SPECIAL > 0 { v |= SPECIAL_BIT; }
+        v
+    }
+    fn from_bits(v: u8) -> u8 {
+        let mut t = 0;
+        if is_mine(v) { t |= T_MINE; }
+        if v & HIDDEN_BIT > 0 { t |= T_HIDDEN; }
+        if v & FLAGGED_BIT > 0 { t |= T_FLAGGED; }
+        if v & SPECIAL_BIT > 0 { t |= T_SPECIAL; }
+        t
+    }
+}
+
+#[derive(Clone)]
 pub struct Board {
-    pub data: Vec<u8>,
+    tiles: Tiles,
+    /// mines around each tile, see `set_tile`
+    numbers: Tiles,
     pub width: NonZeroUsize,
     pub height: NonZeroUsize,
     pub hidden_tiles: usize,
     pub mine_count: usize,
     pub num_tile_reveal: bool,
+    /// offsets revealed by the latest move
+    pub revealed_log: Vec<usize>,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MoveType {
     Reveal,
     ToggleFlag,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Move {
     pub t: MoveType,
     pub pos: BoardPos,
 }
+/// What a move did to the board, for bookkeeping
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MoveOutcome {
+    pub revealed: usize,
+    pub exploded: bool,
+    /// dug up a mine in a Minesweeper Flags game
+    pub found: bool,
+    /// left a flag proper where it was aimed, in the mover's own layer if flags aren't shared
+    pub flag_set: bool,
+    /// cost a life in a hardcore game
+    pub wrong_flag: bool,
+}
 
 impl Game {
     pub fn new(conf: BoardConf) -> Self {
-        let board = Board::new(conf);
+        Self::with_seed(conf, rand::random())
+    }
+    /// Same seed and conf, same mines
+    pub fn with_seed(conf: BoardConf, seed: u64) -> Self {
+        let board = Board::new(conf, seed);
         Game {
             phase: if conf.always_safe_first_move { Phase::SafeFirstMove } else { Phase::Run },
+            bbbv: board.bbbv(),
             board,
-            board_conf: conf
+            board_conf: conf,
+            started: None,
+            ended: None,
+            clicks: 0,
+            hunt: None,
+            layers: HashMap::new(),
+            lives: conf.hardcore_lives,
+        }
+    }
+    /// Minesweeper Flags: mines are what you're after, the first team to
+    /// find most of them wins. No safe first move or number tile revealing.
+    pub fn new_hunt(conf: BoardConf) -> Self {
+        let mut game = Self::new(BoardConf { always_safe_first_move: false, num_tile_reveal: false, ..conf });
+        game.hunt = Some([0, 0]);
+        game
+    }
+    pub fn act(&mut self, m: Move) -> MoveOutcome {
+        self.act_for(m, Actor::default())
+    }
+    /// Make a move on someone's behalf, which matters for team games and flag layers
+    pub fn act_for(&mut self, m: Move, actor: Actor) -> MoveOutcome {
+        let owner = self.flag_owner(actor);
+        let hidden_before = self.board.hidden_tiles;
+        let dead_before = self.phase == Phase::Die;
+        let found_before = self.hunt.map(|h| h[0] + h[1]);
+        let marks_before = self.marks_at(m.pos, owner);
+        let lives_before = self.lives;
+        self.board.revealed_log.clear();
+        self.started.get_or_insert_with(Instant::now);
+        self.apply(m, actor);
+        let marks_after = self.marks_at(m.pos, owner);
+        let outcome = MoveOutcome {
+            revealed: hidden_before.saturating_sub(self.board.hidden_tiles),
+            exploded: !dead_before && self.phase == Phase::Die && !self.out_of_lives(),
+            found: found_before != self.hunt.map(|h| h[0] + h[1]),
+            // locked counts, it's as flagged as it gets
+            flag_set: m.t == MoveType::ToggleFlag && (marks_after == Some(FLAGGED_BIT) || marks_after == Some(SPECIAL_BIT)),
+            wrong_flag: self.lives < lives_before,
+        };
+        let flag_changed = m.t == MoveType::ToggleFlag && marks_before != marks_after;
+        if outcome.revealed > 0 || outcome.exploded || flag_changed || outcome.wrong_flag {
+            self.clicks += 1;
+        }
+        if self.ended.is_none() && (self.phase == Phase::Win || self.phase == Phase::Die) {
+            self.ended = Some(Instant::now());
+        }
+        outcome
+    }
+    pub fn summary(&self) -> GameSummary {
+        let time = match (self.started, self.ended) {
+            (Some(s), Some(e)) => e.duration_since(s).as_secs_f64(),
+            (Some(s), None) => s.elapsed().as_secs_f64(),
+            _ => 0.0,
+        };
+        GameSummary {
+            phase: self.phase,
+            time,
+            bbbv: self.bbbv,
+            bbbv_per_s: if time > 0.0 { self.bbbv as f64 / time } else { 0.0 },
+            clicks: self.clicks,
+            efficiency: if self.clicks > 0 { 100.0 * self.bbbv as f64 / self.clicks as f64 } else { 0.0 },
+        }
+    }
+    /// Whose flags `actor` plays with, none when they're on the board for all to see
+    pub fn flag_owner(&self, actor: Actor) -> Option<FlagOwner> {
+        match self.board_conf.flag_scope {
+            FlagScope::Shared => None,
+            FlagScope::Team => actor.team.map(FlagOwner::Team).or(actor.uid.map(FlagOwner::Player)),
+            FlagScope::Player => actor.uid.map(FlagOwner::Player),
         }
     }
-    pub fn act(&mut self, m: Move) {
+    /// Flag bits on a hidden tile as `owner` sees them
+    fn marks_at(&self, pos: BoardPos, owner: Option<FlagOwner>) -> Option<u8> {
+        let off = pos.rel_offset(&self.board)?;
+        (self.board.tile(off) & HIDDEN_BIT > 0).then(|| self.board.marks(off, self.layer(owner)))
+    }
+    fn layer(&self, owner: Option<FlagOwner>) -> Option<&FlagLayer> {
+        owner.and_then(|o| self.layers.get(&o))
+    }
+    /// The board as `owner` sees it, with their own flags on, and through
+    /// `sight` if the board's fogged and the game's still going
+    pub fn render_for(&self, owner: Option<FlagOwner>, sight: Option<&Sight>, region: Region) -> Vec<u8> {
+        let over = self.phase == Phase::Win || self.phase == Phase::Die;
+        match (self.board_conf.fog_radius, sight) {
+            (Some(r), Some(sight)) if !over => self.board.render_region(self.layer(owner), |off| sight.sees(&self.board, r, off), region),
+            _ => self.board.render_region(self.layer(owner), |_| true, region),
+        }
+    }
+    /// One tile of `render_for`, for patching up what was rendered before
+    pub fn render_tile_for(&self, owner: Option<FlagOwner>, sight: Option<&Sight>, off: usize) -> u8 {
+        let over = self.phase == Phase::Win || self.phase == Phase::Die;
+        let visible = match (self.board_conf.fog_radius, sight) {
+            (Some(r), Some(sight)) if !over => sight.sees(&self.board, r, off),
+            _ => true,
+        };
+        self.board.render_tile(off, self.layer(owner), visible)
+    }
+    /// Mark the rightly flagged mines, on the board and in every layer
+    pub fn grade(&mut self) {
+        self.board.grade();
+        for layer in self.layers.values_mut() {
+            layer.grade(&self.board);
+        }
+    }
+    /// Lost on wrong flags rather than a mine, in a hardcore game
+    pub fn out_of_lives(&self) -> bool {
+        self.phase == Phase::Die && self.lives == Some(0)
+    }
+    /// What ended a lost game, for telling people about it
+    pub fn loss_cause(&self) -> Option<&'static str> {
+        (self.phase == Phase::Die).then(|| if self.out_of_lives() { "flags" } else { "mine" })
+    }
+    /// The team ahead in a Minesweeper Flags game, none on a tie
+    pub fn leading_team(&self) -> Option<Team> {
+        let h = self.hunt?;
+        match h[0].cmp(&h[1]) {
+            std::cmp::Ordering::Greater => Some(Team::A),
+            std::cmp::Ordering::Less => Some(Team::B),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+    fn apply(&mut self, m: Move, actor: Actor) {
         let lost_phase = | phase: &Phase | {
             match *phase {
                 Phase::SafeFirstMove => Phase::FirstMoveFail,
@@ -86,11 +474,46 @@ impl Game {
 
         match m.t {
             MoveType::Reveal => {
-                let kaboom = self.board.reveal(m.pos);
+                let owner = self.flag_owner(actor);
+                let kaboom = self.board.reveal(m.pos, owner.and_then(|o| self.layers.get(&o)));
+                if let (true, Some(hunt)) = (kaboom, self.hunt.as_mut()) {
+                    // a find, not a loss
+                    let team = actor.team.unwrap_or(Team::A);
+                    let off = m.pos.rel_offset_unchecked(&self.board);
+                    self.board.set_tile(off, match team { Team::A => FOUND_A, Team::B => FOUND_B });
+                    hunt[team.index()] += 1;
+                    let mines = self.board.mine_count;
+                    if hunt[team.index()] * 2 > mines || hunt[0] + hunt[1] == mines {
+                        self.phase = Phase::Win;
+                    }
+                    return;
+                }
                 if kaboom { self.phase = lost_phase(&self.phase); }
                 if self.phase == Phase::SafeFirstMove { self.phase = Phase::Run }
             },
-            MoveType::ToggleFlag => self.board.flag(m.pos),
+            MoveType::ToggleFlag => if let Some(off) = m.pos.rel_offset(&self.board) {
+                let owner = self.flag_owner(actor);
+                let hidden = self.board.tile(off) & HIDDEN_BIT > 0;
+                let marks = self.board.marks(off, owner.and_then(|o| self.layers.get(&o)));
+                // bare tiles get flagged next, flags turn into questions and those into nothing
+                let flagging = hidden && marks == 0;
+                match self.lives.as_mut() {
+                    // hardcore: flags get judged on the spot, and the right ones stay put
+                    Some(_) if marks == SPECIAL_BIT => (),
+                    Some(lives) if flagging && !self.board.is_mine_at(off) => {
+                        *lives = lives.saturating_sub(1);
+                        if *lives == 0 { self.phase = Phase::Die; }
+                    },
+                    Some(_) if flagging => match owner {
+                        Some(owner) => self.layers.entry(owner).or_default().lock(off),
+                        None => self.board.set_tile(off, CORRECT),
+                    },
+                    _ => match owner {
+                        Some(owner) => if hidden { self.layers.entry(owner).or_default().cycle(off); },
+                        None => self.board.flag(m.pos),
+                    },
+                }
+            },
         };
 
         if self.phase == Phase::FirstMoveFail {
@@ -98,115 +521,153 @@ impl Game {
             if winnable {
                 self.board.hidden_tiles += 1;
                 self.board.move_mine_elsewhere(m.pos);
+                self.bbbv = self.board.bbbv();
                 self.phase = Phase::Run;
-                self.act(m);
+                self.apply(m, actor);
             } else {
                 self.phase = Phase::Die;
             }
-        } else if self.phase != Phase::Die && self.board.hidden_tiles == self.board.mine_count {
+        } else if self.phase != Phase::Die && self.board.hidden_tiles == self.board.mine_count
+            // Flags games end on finding mines, unless there's none to find
+            && (self.hunt.is_none() || self.board.mine_count == 0) {
             self.phase = Phase::Win;
         } else if self.phase == Phase::Die && self.board_conf.reveal_on_lose {
-            for tile in self.board.data.iter_mut().filter(|x| is_mine(**x)) {
-                *tile = unhide(*tile);
+            for off in 0..self.board.area() {
+                if self.board.is_mine_at(off) {
+                    self.board.set_tile(off, unhide(self.board.tile(off)));
+                }
             }
         }
     }
 }
 impl Board {
-    pub fn new(mut conf: BoardConf) -> Self {
+    pub fn new(mut conf: BoardConf, seed: u64) -> Self {
         let (w,h) = (conf.w,conf.h);
         let area = w.get()*h.get();
         if w.get() < 3 || h.get() < 3 { conf.revealed_borders = false; }
         let mined_area = area - if conf.revealed_borders { 2*(w.get()-1) + 2*(h.get()-1) } else { 0 };
         let mine_count = ((conf.mine_ratio.0 * mined_area) / conf.mine_ratio.1.get()).clamp(0, mined_area);
         let mut b = Board {
-            data: [HIDDEN_BIT].repeat(area),
+            tiles: Tiles::new(area, T_HIDDEN),
+            numbers: Tiles::new(area, 0),
             width: w,
             height: h,
             hidden_tiles: area,
             mine_count,
             num_tile_reveal: conf.num_tile_reveal,
+            revealed_log: vec![],
         };
         if conf.revealed_borders {
             let (w,h) = (w.get(),h.get());
-            b.spread_mines(mine_count, true);
+            b.spread_mines(mine_count, true, seed);
             for x in 0..w {
-                b.reveal((x,   0).try_into().unwrap());
-                b.reveal((x, h-1).try_into().unwrap());
+                b.reveal((x,   0).try_into().unwrap(), None);
+                b.reveal((x, h-1).try_into().unwrap(), None);
             }
             for y in 1..h-1 {
-                b.reveal((  0, y).try_into().unwrap());
-                b.reveal((w-1, y).try_into().unwrap());
+                b.reveal((  0, y).try_into().unwrap(), None);
+                b.reveal((w-1, y).try_into().unwrap(), None);
             }
-        } else { b.spread_mines(mine_count, false); }
+        } else { b.spread_mines(mine_count, false, seed); }
         b
     }
-    pub fn spread_mines(&mut self, mut count: usize, without_edges: bool) {
-        let mut rng = thread_rng();
-        let w = self.width.get() as u32;
-        let h = self.height.get() as u32;
-        let (wr,hr) = if without_edges { ((1,w-1),(1,h-1)) } else { ((0,w),(0,h)) };
-        while count > 0 {
-            let randpos = BoardPos(rng.sample(Uniform::new(wr.0, wr.1)), rng.sample(Uniform::new(hr.0, hr.1)));
-            let o = randpos.rel_offset_unchecked(&self);
-            if self.data[o] == MINED { continue }
-            else {
-                self.data[o] = MINED;
-                count -= 1;
-                self.map_neighs(randpos, |neigh| {
-                    if neigh != MINED {
-                        neigh + 1
-                    } else { neigh }
-                });
+    /// Lay `count` mines on distinct tiles with Floyd's sampling: one draw per mine however
+    /// packed the board, and the board itself remembers which spots are taken
+    pub fn spread_mines(&mut self, count: usize, without_edges: bool, seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let w = self.width.get();
+        let (edge, iw, ih) = if without_edges { (1, w - 2, self.height.get() - 2) } else { (0, w, self.height.get()) };
+        let spot = |i: usize| (edge + i % iw) + (edge + i / iw) * w;
+        let spots = iw * ih;
+        for j in spots - count..spots {
+            let t = spot(rng.gen_range(0..=j));
+            let o = if self.is_mine_at(t) { spot(j) } else { t };
+            self.set_tile(o, MINED);
+        }
+    }
+
+    pub fn area(&self) -> usize {
+        self.width.get() * self.height.get()
+    }
+    /// The tile at `off` as it's always been laid out: flag bits over its number, `NUMBITS` for a mine
+    pub fn tile(&self, off: usize) -> u8 {
+        let t = self.tiles.get(off);
+        let num = if t & T_MINE > 0 { NUMBITS } else { self.mines_around(off) };
+        Tiles::to_bits(t) | num
+    }
+    /// Lay a tile out as `tile` would have it; its number is left to the mines around,
+    /// and if it gained or lost a mine, so are its neighbours'
+    pub fn set_tile(&mut self, off: usize, v: u8) {
+        let was_mine = self.is_mine_at(off);
+        self.tiles.set(off, Tiles::from_bits(v));
+        if is_mine(v) != was_mine {
+            for n in self.neigh_offs(off) {
+                let around = self.numbers.get(n);
+                self.numbers.set(n, if was_mine { around - 1 } else { around + 1 });
             }
         }
     }
+    fn is_hidden(&self, off: usize) -> bool {
+        self.tiles.get(off) & T_HIDDEN > 0
+    }
+    fn mines_around(&self, off: usize) -> u8 {
+        self.numbers.get(off)
+    }
 
-    fn neighs(&self, pos: BoardPos) -> Vec<BoardPos> {
+    /// Offsets of the tiles around `off`. Holds on to nothing of the board's, so it can be changed along the way.
+    fn neigh_offs(&self, off: usize) -> impl Iterator<Item = usize> {
         const NEIGH_OFFS: &[(isize,isize)] = &[
             (-1,-1),(0,-1),(1,-1),
             (-1, 0),       (1, 0),
             (-1, 1),(0, 1),(1, 1),
         ];
-        let ipos: (isize,isize) = pos.try_into().unwrap();
-        NEIGH_OFFS
-            .iter()
-            .filter_map(|(x,y)| (*x + ipos.0, *y + ipos.1).try_into().ok())
-            .filter(|pos: &BoardPos| pos.is_within(&self))
-            .collect()
-    }
-    fn map_neighs<F: FnMut(u8) -> u8>(&mut self, pos: BoardPos, mut f: F) {
-        let neighs: Vec<usize> = self.neighs(pos).iter().filter_map(|pos| pos.rel_offset(&self)).collect();
-        neighs.iter().for_each(|off| { self.data[*off] = f(self.data[*off]); });
+        let (w, h) = (self.width.get(), self.height.get());
+        let (x, y) = (off % w, off / w);
+        NEIGH_OFFS.iter().filter_map(move |(dx,dy)| {
+            let (nx, ny) = (x.checked_add_signed(*dx)?, y.checked_add_signed(*dy)?);
+            (nx < w && ny < h).then_some(nx + ny*w)
+        })
     }
 
-    pub fn flood_reveal(&mut self, pos: BoardPos) -> bool {
-        let mut queue = vec![pos];
-        while let Some(pos) = queue.pop() {
-            let off = pos.rel_offset_unchecked(&self);
-            let c = &mut self.data[off];
+    /// The flag bits on a tile, from `layer` if flags aren't kept on the board itself
+    fn marks(&self, off: usize, layer: Option<&FlagLayer>) -> u8 {
+        match layer {
+            Some(l) => l.get(off),
+            None => Tiles::to_bits(self.tiles.get(off)) & (FLAGGED_BIT | SPECIAL_BIT),
+        }
+    }
+    pub fn flood_reveal(&mut self, pos: BoardPos, layer: Option<&FlagLayer>) -> bool {
+        self.flood_from(pos.rel_offset_unchecked(self), layer)
+    }
+    fn flood_from(&mut self, off: usize, layer: Option<&FlagLayer>) -> bool {
+        let mut queue = vec![off];
+        while let Some(off) = queue.pop() {
+            let m = self.marks(off, layer);
             // don't reveal the already revealed or the flagged, but reveal the questionings
-            let unrevealable = (*c & FLAGGED_BIT > 0) ^ (*c & SPECIAL_BIT > 0);
-            if *c & HIDDEN_BIT > 0 && !unrevealable {
-                *c = unhide(*c);
+            let unrevealable = (m & FLAGGED_BIT > 0) ^ (m & SPECIAL_BIT > 0);
+            if self.is_hidden(off) && !unrevealable {
+                let c = unhide(self.tile(off));
+                self.set_tile(off, c);
                 self.hidden_tiles -= 1;
-                if is_mine(*c) { return true; }
-                if *c > 0 { continue; }
-                queue.append(&mut self.neighs(pos));
+                self.revealed_log.push(off);
+                if is_mine(c) { return true; }
+                if c > 0 { continue; }
+                queue.extend(self.neigh_offs(off).filter(|o| self.is_hidden(*o)));
             }
         }
         false
     }
-    pub fn reveal_numtile(&mut self, pos: BoardPos) -> bool {
-        if let Some(off) = pos.rel_offset(&self) {
-            let count = self.data[off] as usize;
-            if 1 <= count && count <= 8 {
-                let mut neighs = self.neighs(pos);
-                let total_neighs = neighs.len();
-                neighs.retain(|pos| self.data[pos.rel_offset_unchecked(&self)] & (FLAGGED_BIT | SPECIAL_BIT) != FLAGGED_BIT);
-                if (total_neighs - neighs.len()) == count {
-                    for pos in neighs.iter() {
-                        if self.flood_reveal(*pos) {
+    pub fn reveal_numtile(&mut self, pos: BoardPos, layer: Option<&FlagLayer>) -> bool {
+        // flags locked in place by a hardcore game count as much as any
+        let flagged = |m: u8| m == FLAGGED_BIT || m == SPECIAL_BIT;
+        if let Some(off) = pos.rel_offset(self) {
+            let count = self.tile(off) as usize;
+            if (1..=8).contains(&count) {
+                let around = self.neigh_offs(off).filter(|o| flagged(self.marks(*o, layer))).count();
+                if around == count {
+                    // digging never takes a flag off, so what's flagged stays as counted
+                    for o in self.neigh_offs(off) {
+                        if !flagged(self.marks(o, layer)) && self.flood_from(o, layer) {
                             return true;
                         }
                     }
@@ -215,92 +676,123 @@ impl Board {
         }
         false
     }
-    pub fn reveal(&mut self, pos: BoardPos) -> bool {
-        if let Some(off) = pos.rel_offset(&self) {
-            let v = self.data[off];
-            if self.num_tile_reveal && 1 <= v && v <= 8 {
-                self.reveal_numtile(pos)
+    /// Dig at `pos`, going by the flags in `layer` if they're kept off the board
+    pub fn reveal(&mut self, pos: BoardPos, layer: Option<&FlagLayer>) -> bool {
+        if let Some(off) = pos.rel_offset(self) {
+            let v = self.tile(off);
+            if self.num_tile_reveal && (1..=8).contains(&v) {
+                self.reveal_numtile(pos, layer)
             } else {
-                self.flood_reveal(pos)
+                self.flood_reveal(pos, layer)
             }
         } else { false }
     }
 
+    /// Bechtel's Board Benchmark Value: every opening counts once, plus every
+    /// numbered tile that isn't on the edge of one. Only hidden tiles count.
+    /// Worked out when a board's dealt, and again if a first move's mine gets moved away.
+    pub fn bbbv(&self) -> usize {
+        let hidden_safe = |o: usize| self.is_hidden(o) && !self.is_mine_at(o);
+        // a bit per tile, the openings' edges get marked too
+        let mut marks = vec![0u64; self.area().div_ceil(64)];
+        let marked = |marks: &[u64], o: usize| marks[o / 64] & 1 << (o % 64) > 0;
+        let mut openings = 0;
+        for off in 0..self.area() {
+            if marked(&marks, off) || !hidden_safe(off) || self.mines_around(off) != 0 { continue; }
+            openings += 1;
+            marks[off / 64] |= 1 << (off % 64);
+            let mut stack = vec![off];
+            while let Some(o) = stack.pop() {
+                if self.mines_around(o) != 0 { continue; } // the opening's edge
+                for no in self.neigh_offs(o) {
+                    if !marked(&marks, no) && hidden_safe(no) {
+                        marks[no / 64] |= 1 << (no % 64);
+                        stack.push(no);
+                    }
+                }
+            }
+        }
+        let loose = (0..self.area())
+            .filter(|o| !marked(&marks, *o) && hidden_safe(*o))
+            .count();
+        openings + loose
+    }
+
     pub fn grade(&mut self) {
-        for i in &mut self.data {
-            if *i == MINED | FLAGGED_BIT {
-                *i = CORRECT;
+        for off in 0..self.area() {
+            if self.is_mine_at(off) && self.tile(off) == MINED | FLAGGED_BIT {
+                self.set_tile(off, CORRECT);
             }
         }
     }
+    pub fn is_mine_at(&self, off: usize) -> bool {
+        self.tiles.get(off) & T_MINE > 0
+    }
     pub fn flag(&mut self, pos: BoardPos) {
-        if let Some(off) = pos.rel_offset(&self) {
+        if let Some(off) = pos.rel_offset(self) {
             const TOPBIT_MASK: u8 = !(NUMBITS | HIDDEN_BIT);
-            let c = &mut self.data[off];
-            if *c & HIDDEN_BIT > 0 {
-                let new_topbits = match *c & (TOPBIT_MASK) {
+            let c = self.tile(off);
+            if c & HIDDEN_BIT > 0 {
+                let new_topbits = match c & (TOPBIT_MASK) {
                     FLAGGED_BIT => QUESTION,
                     QUESTION => 0,
                     _ => FLAGGED_BIT,
                 } | HIDDEN_BIT;
-                *c = (*c & NUMBITS) | new_topbits;
+                self.set_tile(off, (c & NUMBITS) | new_topbits);
             }
         }
     }
 
     pub fn render(&self) -> Vec<u8> {
-        let mut ret = vec![];
-        for y in 0..self.height.get() {
-            for x in 0..self.width.get() {
-                let pos: BoardPos = (x,y).try_into().unwrap();
-                let c = &self.data[pos.rel_offset_unchecked(&self)];
-                const QUESTION_MASK: u8 = SPECIAL_BIT | FLAGGED_BIT;
-                match *c {
-                    0 => ret.push(b' '),
-                    _ if *c <= 8 => ret.push(b'0' + c),
-                    _ if (*c & QUESTION_MASK) == QUESTION_MASK => ret.push(b'Q'),
-                    _ if (*c & SPECIAL_BIT) > 0 => ret.push(b'C'),
-                    _ if (*c & FLAGGED_BIT) > 0 => ret.push(b'F'),
-                    _ if (*c & HIDDEN_BIT) > 0 => ret.push(b'#'),
-                    _ if *c == NUMBITS => ret.push(b'O'),
-                    _ => ret.push(b'?'),
-                }
+        self.render_region(None, |_| true, Region::whole(self))
+    }
+    /// Render `region` with the flags from `layer` laid over the hidden tiles,
+    /// and revealed tiles that aren't `visible` lost in the fog
+    pub fn render_region(&self, layer: Option<&FlagLayer>, visible: impl Fn(usize) -> bool, region: Region) -> Vec<u8> {
+        let mut ret = Vec::with_capacity((region.w + 4) * region.h);
+        for y in region.y..region.y + region.h {
+            for x in region.x..region.x + region.w {
+                let off = x + y * self.width.get();
+                ret.push(self.render_tile(off, layer, visible(off)));
             }
             ret.extend_from_slice(b"<br>");
         }
         ret
     }
+    /// One tile as `render_region` lays it out
+    pub fn render_tile(&self, off: usize, layer: Option<&FlagLayer>, visible: bool) -> u8 {
+        let c = match (self.tile(off), layer) {
+            (c, Some(l)) if c & HIDDEN_BIT > 0 => (c & !(FLAGGED_BIT | SPECIAL_BIT)) | l.get(off),
+            (c, _) => c,
+        };
+        const QUESTION_MASK: u8 = SPECIAL_BIT | FLAGGED_BIT;
+        match c {
+            _ if c & HIDDEN_BIT == 0 && !visible => b'~',
+            0 => b' ',
+            _ if c <= 8 => b'0' + c,
+            FOUND_A => b'A',
+            FOUND_B => b'B',
+            _ if (c & QUESTION_MASK) == QUESTION_MASK => b'Q',
+            _ if (c & SPECIAL_BIT) > 0 => b'C',
+            _ if (c & FLAGGED_BIT) > 0 => b'F',
+            _ if (c & HIDDEN_BIT) > 0 => b'#',
+            _ if c == NUMBITS => b'O',
+            _ => b'?',
+        }
+    }
 
     pub fn move_mine_elsewhere(&mut self, pos: BoardPos) {
-        let mut surround_count = 0;
-        self.map_neighs(pos, |val| {
-            if (val & !FLAGGED_BIT) == MINED {
-                surround_count += 1;
-                val
-            } else {
-                val - 1
-            }});
-        let off = pos.rel_offset_unchecked(&self);
-        let vacant_pos = {
-            let v = self.data.iter()
-                .enumerate()
-                .filter(|(_,val)| (*val & NUMBITS) != NUMBITS)
-                .map(|(p,_)| p)
-                .next()
-                .unwrap(); // there must be at least one
-            BoardPos((v%self.width.get()) as u32, (v/self.width.get()) as u32)
-        };
-        let voff = vacant_pos.rel_offset_unchecked(&self);
+        let off = pos.rel_offset_unchecked(self);
+        let voff = (0..self.area())
+            .find(|o| !self.is_mine_at(*o))
+            .unwrap(); // there must be at least one
         debug_assert!(voff != off, "swapped mine to the same position in a FirstMoveFail/grace'd first move (???)");
 
         { // swap 'em (keep these together, pls kthnx (bugs were had))
-            self.data[voff] |= MINED;
-            self.data[off] = surround_count;
+            self.set_tile(voff, self.tile(voff) | MINED);
+            // the numbers around both follow on their own, and the move that hit it digs it up again
+            self.set_tile(off, HIDDEN_BIT);
         }
-
-        self.map_neighs(vacant_pos, |val| {
-            if (val & !FLAGGED_BIT) == MINED { val } else { val + 1 }
-        });
     }
 }
 
@@ -337,4 +829,257 @@ pub fn unhide(tile: u8) -> u8 {
     tile & NUMBITS
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A hidden `w`x`h` board with mines at `mines`, nothing else on it
+    fn board(w: usize, h: usize, mines: &[(usize,usize)]) -> Board {
+        let mut b = Board {
+            tiles: Tiles::new(w*h, T_HIDDEN),
+            numbers: Tiles::new(w*h, 0),
+            width: NonZeroUsize::new(w).unwrap(),
+            height: NonZeroUsize::new(h).unwrap(),
+            hidden_tiles: w*h,
+            mine_count: mines.len(),
+            num_tile_reveal: false,
+            revealed_log: vec![],
+        };
+        for (x,y) in mines {
+            b.set_tile(x + y*w, MINED);
+        }
+        b
+    }
+
+    /// A game on `board(w, h, mines)` with nothing special about it but how flags are kept
+    fn game(w: usize, h: usize, mines: &[(usize,usize)], flag_scope: FlagScope) -> Game {
+        let conf = BoardConf {
+            w: NonZeroUsize::new(w).unwrap(), h: NonZeroUsize::new(h).unwrap(),
+            mine_ratio: (0, NonZeroUsize::new(1).unwrap()),
+            always_safe_first_move: false, revealed_borders: false, reveal_on_lose: false, num_tile_reveal: false,
+            flag_scope, fog_radius: None, hardcore_lives: None,
+        };
+        let mut g = Game::with_seed(conf, 0);
+        g.board = board(w, h, mines);
+        g.bbbv = g.board.bbbv();
+        g
+    }
+    fn mv(t: MoveType, x: u32, y: u32) -> Move {
+        Move { t, pos: (x,y).try_into().unwrap() }
+    }
+    fn player(uid: usize, team: Option<Team>) -> Actor {
+        Actor { uid: Some(uid), team }
+    }
+    fn seen_by(g: &Game, actor: Actor) -> String {
+        String::from_utf8(g.render_for(g.flag_owner(actor), None, Region::whole(&g.board))).unwrap()
+    }
+
+    #[test]
+    fn bbbv_counts_an_empty_board_as_one_opening() {
+        assert_eq!(board(3, 3, &[]).bbbv(), 1);
+    }
 
+    #[test]
+    fn bbbv_counts_every_number_off_an_opening() {
+        assert_eq!(board(3, 3, &[(1,1)]).bbbv(), 8);
+    }
+
+    #[test]
+    fn bbbv_counts_openings_and_their_edges_once() {
+        // 0 1 * 1 0
+        assert_eq!(board(5, 1, &[(2,0)]).bbbv(), 2);
+        // 0 1 * 1 * 1 0: the 1 in the middle touches no opening
+        assert_eq!(board(7, 1, &[(2,0), (4,0)]).bbbv(), 3);
+    }
+
+    #[test]
+    fn bbbv_skips_what_is_already_revealed() {
+        let mut b = board(5, 1, &[(2,0)]);
+        assert!(!b.reveal((0,0).try_into().unwrap(), None));
+        assert_eq!(b.bbbv(), 1);
+    }
+
+    #[test]
+    fn player_flags_only_show_to_their_planter() {
+        let mut g = game(3, 1, &[(2,0)], FlagScope::Player);
+        let (a, b) = (player(1, None), player(2, None));
+        assert!(g.act_for(mv(MoveType::ToggleFlag, 0, 0), a).flag_set);
+        assert_eq!(seen_by(&g, a), "F##<br>");
+        assert_eq!(seen_by(&g, b), "###<br>");
+        assert_eq!(g.board.render(), b"###<br>", "the board itself stays clean");
+    }
+
+    #[test]
+    fn layer_flags_guard_only_their_owner() {
+        let mut g = game(3, 1, &[(2,0)], FlagScope::Player);
+        let (a, b) = (player(1, None), player(2, None));
+        g.act_for(mv(MoveType::ToggleFlag, 0, 0), a);
+        assert_eq!(g.act_for(mv(MoveType::Reveal, 0, 0), a).revealed, 0);
+        assert_eq!(g.act_for(mv(MoveType::Reveal, 0, 0), b).revealed, 2);
+    }
+
+    #[test]
+    fn layer_flags_cycle_like_board_flags() {
+        let mut g = game(3, 1, &[(2,0)], FlagScope::Player);
+        let a = player(1, None);
+        let seen = ["F##<br>", "Q##<br>", "###<br>"];
+        for want in seen {
+            g.act_for(mv(MoveType::ToggleFlag, 0, 0), a);
+            assert_eq!(seen_by(&g, a), want);
+        }
+    }
+
+    #[test]
+    fn team_flags_are_shared_within_the_team() {
+        let mut g = game(3, 1, &[(2,0)], FlagScope::Team);
+        let (a1, a2, b1) = (player(1, Some(Team::A)), player(2, Some(Team::A)), player(3, Some(Team::B)));
+        g.act_for(mv(MoveType::ToggleFlag, 2, 0), a1);
+        assert_eq!(seen_by(&g, a2), "##F<br>");
+        assert_eq!(seen_by(&g, b1), "###<br>");
+    }
+
+    #[test]
+    fn grading_marks_right_layer_flags() {
+        let mut g = game(3, 1, &[(2,0)], FlagScope::Player);
+        let a = player(1, None);
+        g.act_for(mv(MoveType::ToggleFlag, 2, 0), a);
+        g.act_for(mv(MoveType::ToggleFlag, 1, 0), a);
+        g.grade();
+        let l = &g.layers[&FlagOwner::Player(1)];
+        assert_eq!(l.get(2), SPECIAL_BIT);
+        assert_eq!(l.get(1), FLAGGED_BIT);
+    }
+
+    fn hardcore(w: usize, h: usize, mines: &[(usize,usize)], flag_scope: FlagScope, lives: usize) -> Game {
+        let mut g = game(w, h, mines, flag_scope);
+        g.board.num_tile_reveal = true;
+        g.board_conf.num_tile_reveal = true;
+        g.board_conf.hardcore_lives = Some(lives);
+        g.lives = Some(lives);
+        g
+    }
+
+    #[test]
+    fn chording_counts_locked_flags() {
+        for scope in [FlagScope::Shared, FlagScope::Player] {
+            // * 1 0 0, with the 1 dug and the mine flagged, which locks it
+            let mut g = hardcore(4, 1, &[(0,0)], scope, 3);
+            let a = player(1, None);
+            g.act_for(mv(MoveType::Reveal, 1, 0), a);
+            assert_eq!(g.board.hidden_tiles, 3);
+            assert!(g.act_for(mv(MoveType::ToggleFlag, 0, 0), a).flag_set);
+            let out = g.act_for(mv(MoveType::Reveal, 1, 0), a);
+            assert!(!out.exploded);
+            assert_eq!(g.phase, Phase::Win, "{scope:?}");
+        }
+    }
+
+    #[test]
+    fn running_out_of_lives_is_no_explosion() {
+        let mut g = hardcore(3, 1, &[(2,0)], FlagScope::Shared, 1);
+        let out = g.act(mv(MoveType::ToggleFlag, 0, 0));
+        assert!(out.wrong_flag && !out.exploded);
+        assert_eq!(g.loss_cause(), Some("flags"));
+
+        let mut g = hardcore(3, 1, &[(2,0)], FlagScope::Shared, 1);
+        assert!(g.act(mv(MoveType::Reveal, 2, 0)).exploded);
+        assert_eq!(g.loss_cause(), Some("mine"));
+    }
+
+    #[test]
+    fn tiles_keep_what_they_are_set_to() {
+        // a mine in the middle, so every other tile reads a 1
+        let mut b = board(3, 3, &[(1,1)]);
+        for v in [MINED, MINED | FLAGGED_BIT, CORRECT, FOUND_A, FOUND_B, NUMBITS] {
+            b.set_tile(4, v);
+            assert_eq!(b.tile(4), v, "{v:#x}");
+        }
+        for v in [HIDDEN_BIT, HIDDEN_BIT | FLAGGED_BIT, HIDDEN_BIT | QUESTION, 0] {
+            b.set_tile(3, v);
+            assert_eq!(b.tile(3), v | 1, "{v:#x}");
+        }
+        // two to a byte, neither steps on the other
+        b.set_tile(2, HIDDEN_BIT | FLAGGED_BIT);
+        b.set_tile(3, 0);
+        assert_eq!(b.tile(2), HIDDEN_BIT | FLAGGED_BIT | 1);
+        assert_eq!(b.tile(3), 1);
+        assert_eq!(b.tile(4), NUMBITS);
+    }
+
+    #[test]
+    fn neighbours_stay_on_the_board() {
+        let b = board(4, 3, &[]);
+        let around = |off| { let mut n = b.neigh_offs(off).collect::<Vec<_>>(); n.sort(); n };
+        assert_eq!(around(0), [1, 4, 5]);
+        assert_eq!(around(3), [2, 6, 7]);
+        assert_eq!(around(11), [6, 7, 10]);
+        assert_eq!(around(1), [0, 2, 4, 5, 6]);
+        assert_eq!(around(4), [0, 1, 5, 8, 9]);
+        assert_eq!(around(5), [0, 1, 2, 4, 6, 8, 9, 10]);
+        let thin = board(1, 3, &[]);
+        assert_eq!(thin.neigh_offs(1).collect::<Vec<_>>(), [0, 2]);
+    }
+
+    #[test]
+    fn mines_land_on_distinct_tiles_evenly() {
+        let mines = |b: &Board| (0..b.area()).filter(|o| b.is_mine_at(*o)).count();
+        let mut b = board(10, 10, &[]);
+        b.spread_mines(90, false, 3);
+        assert_eq!(mines(&b), 90);
+        let mut b = board(10, 10, &[]);
+        b.spread_mines(64, true, 3);
+        assert_eq!(mines(&b), 64, "the whole inside");
+        assert!((0..10).all(|i| [i, 90 + i, i * 10, i * 10 + 9].iter().all(|o| !b.is_mine_at(*o))));
+        // every tile about as likely as the next
+        let mut hits = [0; 25];
+        for seed in 0..4000 {
+            let mut b = board(5, 5, &[]);
+            b.spread_mines(5, false, seed);
+            (0..25).filter(|o| b.is_mine_at(*o)).for_each(|o| hits[o] += 1);
+        }
+        assert!(hits.iter().all(|h| (650..950).contains(h)), "{hits:?}");
+    }
+
+    #[test]
+    fn flags_without_mines_end_once_cleared() {
+        let mut g = game(3, 3, &[], FlagScope::Shared);
+        g.hunt = Some([0, 0]);
+        g.act_for(mv(MoveType::Reveal, 1, 1), player(1, Some(Team::A)));
+        assert_eq!(g.phase, Phase::Win);
+
+        // with mines about, it's the finds that end it
+        let mut g = game(3, 1, &[(2,0)], FlagScope::Shared);
+        g.hunt = Some([0, 0]);
+        g.act_for(mv(MoveType::Reveal, 0, 0), player(1, Some(Team::A)));
+        assert_eq!(g.phase, Phase::Run);
+        g.act_for(mv(MoveType::Reveal, 2, 0), player(1, Some(Team::A)));
+        assert_eq!(g.phase, Phase::Win);
+    }
+
+    #[test]
+    fn numbers_follow_the_mines() {
+        let mut b = board(3, 3, &[(1,1), (2,2)]);
+        assert_eq!((0..9).map(|o| b.tile(o) & NUMBITS).collect::<Vec<_>>(), [1, 1, 1, 1, NUMBITS, 2, 1, 2, NUMBITS]);
+        // found, flagged or unhidden, a mine's still a mine
+        b.set_tile(4, FOUND_A);
+        b.set_tile(8, MINED | FLAGGED_BIT);
+        assert_eq!(b.tile(5) & NUMBITS, 2);
+        b.set_tile(4, HIDDEN_BIT);
+        assert_eq!((0..9).map(|o| b.tile(o) & NUMBITS).collect::<Vec<_>>(), [0, 0, 0, 0, 1, 1, 0, 1, NUMBITS]);
+        b.move_mine_elsewhere((2, 2).try_into().unwrap());
+        assert_eq!(b.tile(8) & NUMBITS, 0);
+        let recount = |o: usize| b.neigh_offs(o).filter(|n| b.is_mine_at(*n)).count() as u8;
+        assert!((0..9).filter(|o| !b.is_mine_at(*o)).all(|o| b.tile(o) == HIDDEN_BIT | recount(o)));
+    }
+
+    #[test]
+    fn safe_first_moves_dig_where_the_mine_was() {
+        let mut g = game(3, 3, &[(1,1)], FlagScope::Shared);
+        g.phase = Phase::SafeFirstMove;
+        g.act(mv(MoveType::Reveal, 1, 1));
+        assert_eq!(g.phase, Phase::Run);
+        assert!(!g.board.is_mine_at(4) && g.board.is_mine_at(0));
+        assert_eq!(g.board.tile(4), 1, "dug up, and counting the mine's new spot");
+        assert_eq!(g.board.hidden_tiles, (0..9).filter(|o| g.board.is_hidden(*o)).count());
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::{ HashMap, VecDeque },
+    fmt::Display,
+    sync::{ Arc, Mutex },
+};
+use tokio::sync::Notify;
+use warp::ws::Message;
+use crate::livepos;
+
+// Per-connection outbound queue. Boards and cursor positions are only ever
+// interesting in their latest form, so a slow reader gets those coalesced
+// instead of queued; everything else is kept in order up to `cap` entries,
+// past which the reader is considered a lost cause and the outbox closes.
+// What's queued is shared: a broadcast is made once and every outbox holds a
+// reference to it, only copied when it goes out on the wire. That copy can't be
+// helped with the warp we're on, its `Message` owns a `Vec<u8>` and won't take
+// shared bytes. Still, the expensive part was serializing and deflating: the
+// broadcast bench puts a 150x150 board to 30 players at about 5ms deflated for
+// each of them, against 0.16ms deflated once and copied 30 times.
+
+/// A message as it sits in outboxes, possibly many of them
+pub type Frame = Arc<Message>;
+
+enum Outgoing {
+    Board(Frame),
+    /// changes on top of the board before it, gone with it when a newer board comes
+    Delta(Frame),
+    Pos(Arc<livepos::PosBatch>),
+    Msg(Frame),
+}
+
+#[derive(Debug)]
+pub enum OutboxError {
+    Closed,
+    Lagged,
+}
+impl Display for OutboxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboxError::Closed => write!(f, "connection closed"),
+            OutboxError::Lagged => write!(f, "client fell too far behind"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct OutboxState {
+    queue: VecDeque<Outgoing>,
+    closed: bool,
+    pos_format: livepos::PosFormat,
+}
+
+pub struct Outbox {
+    state: Mutex<OutboxState>,
+    wake: Notify,
+    cap: usize,
+}
+
+impl std::fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let st = self.state.lock().unwrap();
+        write!(f, "Outbox {{ queued: {}/{}, closed: {} }}", st.queue.len(), self.cap, st.closed)
+    }
+}
+
+impl Outbox {
+    pub fn new(cap: usize) -> Self {
+        Outbox { state: Mutex::new(OutboxState::default()), wake: Notify::new(), cap }
+    }
+
+    fn push(&self, f: impl FnOnce(&mut VecDeque<Outgoing>) -> Option<Outgoing>) -> Result<(), OutboxError> {
+        let mut st = self.state.lock().unwrap();
+        if st.closed { return Err(OutboxError::Closed); }
+        if let Some(leftover) = f(&mut st.queue) {
+            if st.queue.len() >= self.cap {
+                st.closed = true;
+                st.queue.clear();
+                drop(st);
+                self.wake.notify_one();
+                return Err(OutboxError::Lagged);
+            }
+            st.queue.push_back(leftover);
+        }
+        drop(st);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Queue a message, in order with the other non-coalesced ones
+    pub fn send(&self, m: impl Into<Frame>) -> Result<(), OutboxError> {
+        let m = m.into();
+        self.push(|_| Some(Outgoing::Msg(m)))
+    }
+
+    /// Queue a board dump, dropping any older board that hasn't gone out yet and the deltas on top of it
+    pub fn send_board(&self, m: impl Into<Frame>) -> Result<(), OutboxError> {
+        let m = m.into();
+        self.push(|q| {
+            q.retain(|o| !matches!(o, Outgoing::Board(_) | Outgoing::Delta(_)));
+            Some(Outgoing::Board(m))
+        })
+    }
+
+    /// Queue changes to the last board queued, so they never go out ahead of it
+    pub fn send_delta(&self, m: impl Into<Frame>) -> Result<(), OutboxError> {
+        let m = m.into();
+        self.push(|_| Some(Outgoing::Delta(m)))
+    }
+
+    /// Queue cursor positions, merged into the pending update if there's one
+    pub fn send_pos(&self, batch: Arc<livepos::PosBatch>) -> Result<(), OutboxError> {
+        self.push(|q| {
+            if let Some(Outgoing::Pos(pending)) = q.iter_mut().find(|o| matches!(o, Outgoing::Pos(_))) {
+                // a slow reader gets their own copy, the shared one stays as it is
+                let mut merged = pending.positions.iter().copied().collect::<HashMap<_,_>>();
+                merged.extend(batch.positions.iter().copied());
+                *pending = livepos::PosBatch::new(merged.into_iter().collect());
+                None
+            } else {
+                Some(Outgoing::Pos(batch))
+            }
+        })
+    }
+
+    /// Switch how positions get encoded from here on
+    pub fn set_pos_format(&self, f: livepos::PosFormat) {
+        self.state.lock().unwrap().pos_format = f;
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.wake.notify_one();
+    }
+
+    /// Next message to put on the wire, None once the outbox is closed and drained
+    pub async fn recv(&self) -> Option<Message> {
+        loop {
+            {
+                let mut st = self.state.lock().unwrap();
+                if let Some(o) = st.queue.pop_front() {
+                    let frame = match o {
+                        Outgoing::Board(m) | Outgoing::Delta(m) | Outgoing::Msg(m) => m,
+                        Outgoing::Pos(p) => p.frame(st.pos_format),
+                    };
+                    return Some(Arc::unwrap_or_clone(frame));
+                }
+                if st.closed { return None; }
+            }
+            self.wake.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(m: &Message) -> &str {
+        m.to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn boards_coalesce_behind_messages() {
+        let ob = Outbox::new(8);
+        ob.send_board(Message::text("board 1")).unwrap();
+        ob.send(Message::text("hello")).unwrap();
+        ob.send_board(Message::text("board 2")).unwrap();
+        assert_eq!(text(&ob.recv().await.unwrap()), "hello");
+        assert_eq!(text(&ob.recv().await.unwrap()), "board 2");
+    }
+
+    #[tokio::test]
+    async fn deltas_never_outrun_their_board() {
+        let ob = Outbox::new(8);
+        ob.send_board(Message::text("board 1")).unwrap();
+        ob.send_delta(Message::text("delta 1")).unwrap();
+        ob.send(Message::text("view 2")).unwrap();
+        ob.send_board(Message::text("board 2")).unwrap();
+        ob.send_delta(Message::text("delta 2")).unwrap();
+        let mut got = vec![];
+        while let Ok(Some(m)) = tokio::time::timeout(std::time::Duration::from_millis(10), ob.recv()).await {
+            got.push(text(&m).to_string());
+        }
+        assert_eq!(got, vec!["view 2", "board 2", "delta 2"]);
+    }
+
+    #[tokio::test]
+    async fn positions_merge_latest_wins() {
+        let ob = Outbox::new(8);
+        ob.send_pos(livepos::PosBatch::new(vec![(1, (10, 10)), (2, (20, 20))])).unwrap();
+        ob.send_pos(livepos::PosBatch::new(vec![(2, (30, 30))])).unwrap();
+        let m = ob.recv().await.unwrap();
+        let mut got: Vec<(usize, (u32,u32))> = serde_json::from_str(text(&m).strip_prefix("pos ").unwrap()).unwrap();
+        got.sort();
+        assert_eq!(got, vec![(1, (10, 10)), (2, (30, 30))]);
+    }
+
+    #[tokio::test]
+    async fn closed_outbox_drains_then_ends() {
+        let ob = Outbox::new(8);
+        ob.send(Message::text("last words")).unwrap();
+        ob.close();
+        assert!(matches!(ob.send(Message::text("too late")), Err(OutboxError::Closed)));
+        assert_eq!(text(&ob.recv().await.unwrap()), "last words");
+        assert!(ob.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn overflowing_closes() {
+        let ob = Outbox::new(2);
+        ob.send(Message::text("a")).unwrap();
+        ob.send(Message::text("b")).unwrap();
+        assert!(matches!(ob.send(Message::text("c")), Err(OutboxError::Lagged)));
+        assert!(ob.recv().await.is_none());
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio::time::Instant;
+use warp::ws::Message;
+use serde::Serialize;
+use crate::types::*;
+use crate::{ minesweeper, ircbot, leaderboard };
+use crate::gameloop::board_message;
+use crate::livepos::LivePos;
+use crate::room::{ self, Dealing, Mode };
+use minesweeper::{ Game, Phase };
+
+// Race mode: every player clears their own copy of the same board, boards
+// only go to their owner and everyone follows along through a progress feed.
+
+struct Racer {
+    uid: usize,
+    name: String,
+    game: Game,
+    /// made a move this race, whoever hasn't isn't waited on
+    moved: bool,
+    /// their board changed since it was last sent
+    dirty: bool,
+    /// seconds into the race when they won or blew up
+    finished: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Standing<'a> {
+    uid: usize,
+    name: &'a str,
+    /// share of the safe tiles revealed, 0 to 100
+    percent: f64,
+    state: &'static str,
+    time: Option<f64>,
+}
+
+impl Racer {
+    fn standing(&self) -> Standing<'_> {
+        let b = &self.game.board;
+        let area = b.width.get() * b.height.get();
+        let safe = area - b.mine_count;
+        let percent = if safe == 0 { 100.0 } else { 100.0 * (area - b.hidden_tiles) as f64 / safe as f64 };
+        let state = match self.game.phase {
+            Phase::Win => "won",
+            Phase::Die => "dead",
+            _ => "alive",
+        };
+        Standing { uid: self.uid, name: &self.name, percent: percent.min(100.0), state, time: self.finished }
+    }
+}
+
+/// Winners by time, then the fallen by how far they got
+fn ranking(racers: &HashMap<SocketAddr, Racer>) -> Vec<Standing<'_>> {
+    let mut rows = racers.values().map(Racer::standing).collect::<Vec<_>>();
+    rows.sort_by(|a, b| {
+        let won = |s: &Standing| s.state == "won";
+        won(b).cmp(&won(a))
+            .then_with(|| if won(a) {
+                a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal)
+            } else {
+                b.percent.partial_cmp(&a.percent).unwrap_or(std::cmp::Ordering::Equal)
+            })
+    });
+    rows
+}
+
+fn send_to(p: &Player, m: Message) {
+    if let Err(e) = p.conn.tx.send(m) {
+        println!("couldn't send race update to {p}: {e}");
+    }
+}
+
+/// Their own board, and what's left of their lives
+fn send_board(p: &Player, racer: &mut Racer) {
+    racer.dirty = false;
+    if let Err(e) = p.conn.tx.send_board(board_message(&racer.game.board)) {
+        println!("couldn't send race board to {p}: {e}");
+    }
+    if let Some(lives) = racer.game.lives {
+        send_to(p, Message::text(format!("lives {lives}")));
+    }
+}
+
+pub struct Race {
+    irc_tx: ircbot::IrcCmdTx,
+    lb_tx: leaderboard::LbCmdTx,
+    room_name: String,
+    bconf: minesweeper::BoardConf,
+    next_bconf: Option<minesweeper::BoardConf>,
+    /// what everyone starts from, and what spectators get to look at
+    pristine: Game,
+    racers: HashMap<SocketAddr, Racer>,
+    race_start: Option<Instant>,
+    ranked: bool,
+    /// someone's standing changed, or who's racing did, the feed should hear about it
+    changed: bool,
+    /// the next board's being dealt, the handle's only here until the room takes it
+    resetting: bool,
+    dealing: Option<Dealing>,
+}
+
+fn finished(g: &Game) -> bool {
+    g.phase == Phase::Win || g.phase == Phase::Die
+}
+
+/// Everyone who joined in is done. Racers who never moved, idlers and latecomers, can't hold the room up
+fn race_over(racers: &HashMap<SocketAddr, Racer>) -> bool {
+    let mut running = racers.values().filter(|r| r.moved).peekable();
+    running.peek().is_some() && running.all(|r| finished(&r.game))
+}
+
+impl Race {
+    pub fn new(irc_tx: ircbot::IrcCmdTx, lb_tx: leaderboard::LbCmdTx, room_conf: RoomConf) -> Self {
+        let bconf = room_conf.board_conf;
+        Race {
+            irc_tx, lb_tx,
+            room_name: room_conf.name,
+            bconf,
+            next_bconf: None,
+            pristine: Game::new(bconf),
+            racers: HashMap::new(),
+            race_start: None,
+            ranked: false,
+            changed: false,
+            resetting: false,
+            dealing: None,
+        }
+    }
+}
+
+impl Mode for Race {
+    fn handle(&mut self, req: MetaMove, peers: &PlayerMap, _livepos: &mut LivePos) {
+        if let MetaMove::Cursor(..) | MetaMove::View(..) = req { return; } // no fog or views in races
+        let bconf = self.bconf;
+        // newcomers get their copy of the board, leavers' copies go away
+        let racing = self.racers.len();
+        self.racers.retain(|addr, _| peers.contains_key(addr));
+        self.changed |= self.racers.len() != racing;
+        for p in peers.values().filter(|p| !p.spectator) {
+            if self.racers.contains_key(&p.conn.addr) { continue; }
+            self.racers.insert(p.conn.addr, Racer {
+                uid: p.uid, name: p.name.clone(), game: self.pristine.clone(), moved: false, dirty: false, finished: None,
+            });
+            self.changed = true;
+        }
+        match req {
+            MetaMove::Move(m, o) => {
+                let (Some(racer), Some(me)) = (self.racers.get_mut(&o), peers.get(&o)) else { return };
+                if finished(&racer.game) { return; }
+                let start = *self.race_start.get_or_insert_with(Instant::now);
+                racer.moved = true;
+                let hidden = racer.game.board.hidden_tiles;
+                racer.game.act(m);
+                racer.dirty = true;
+                let done = finished(&racer.game);
+                self.changed |= done || racer.game.board.hidden_tiles != hidden;
+                if done {
+                    // the last board goes out right away, ahead of the verdict
+                    racer.game.grade();
+                    send_board(me, racer);
+                    racer.finished = Some(start.elapsed().as_secs_f64());
+                    let mut o = serde_json::to_value(racer.game.summary()).expect("couldn't JSONify game summary");
+                    o["player"] = racer.name.clone().into();
+                    if let Some(cause) = racer.game.loss_cause() { o["cause"] = cause.into(); }
+                    if racer.game.phase == Phase::Win {
+                        send_to(me, Message::text(format!("win {o}")));
+                        let _ = self.irc_tx.send(ircbot::IrcCmd::GameWin(racer.name.clone()));
+                        let sum = racer.game.summary();
+                        let entry = leaderboard::Entry {
+                            time: sum.time, bbbv: sum.bbbv, bbbv_per_s: sum.bbbv_per_s, efficiency: sum.efficiency,
+                            players: vec![racer.name.clone()], room: self.room_name.clone(), when: leaderboard::now_unix(),
+                        };
+                        if let Err(e) = self.lb_tx.send(leaderboard::LbCmd::Record(bconf.leaderboard_key("race"), entry)) {
+                            println!("couldn't record race win on the leaderboard: {e}");
+                        }
+                    } else {
+                        send_to(me, Message::text(format!("lose {o}")));
+                        let name = racer.name.clone();
+                        let _ = self.irc_tx.send(if racer.game.out_of_lives() { ircbot::IrcCmd::GameOutOfLives(name) } else { ircbot::IrcCmd::GameLose(name) });
+                    }
+                }
+            },
+            MetaMove::StateDump => {
+                for p in peers.values() {
+                    send_to(p, Message::text(format!("bconf {bconf}")));
+                    let game = self.racers.get(&p.conn.addr).map(|r| &r.game).unwrap_or(&self.pristine);
+                    if let Err(e) = p.conn.tx.send_board(board_message(&game.board)) {
+                        println!("couldn't send race board to {p}: {e}");
+                    }
+                    if let Some(lives) = game.lives {
+                        send_to(p, Message::text(format!("lives {lives}")));
+                    }
+                }
+            },
+            MetaMove::Reset => {
+                if race_over(&self.racers) && !self.resetting {
+                    if let Some(newconf) = self.next_bconf.take() { self.bconf = newconf; }
+                    let bconf = self.bconf;
+                    self.dealing = Some(room::deal(move || Game::new(bconf)));
+                    self.resetting = true;
+                }
+            },
+            MetaMove::Reconf(newconf) => self.next_bconf = Some(newconf),
+            MetaMove::PlayersChanged => (), // the sync above took care of it
+            MetaMove::Cursor(..) | MetaMove::View(..) => unreachable!(),
+        }
+    }
+
+    fn flush(&mut self, peers: &PlayerMap, _livepos: &mut LivePos) {
+        for (addr, racer) in self.racers.iter_mut().filter(|(_, r)| r.dirty) {
+            if let Some(p) = peers.get(addr) { send_board(p, racer); }
+        }
+        if !self.changed { return; }
+        self.changed = false;
+        let standings = ranking(&self.racers);
+        let feed = Message::text(format!("progress {}", serde_json::to_string(&standings).expect("couldn't JSONify race progress")));
+        let over = race_over(&self.racers);
+        let result = (over && !self.ranked).then(|| {
+            Message::text(format!("ranking {}", serde_json::to_string(&standings).expect("couldn't JSONify race ranking")))
+        });
+        self.ranked |= over;
+        peers.broadcast(feed);
+        if let Some(r) = result { peers.broadcast(r); }
+    }
+
+    fn take_dealing(&mut self) -> Option<Dealing> {
+        self.dealing.take()
+    }
+
+    fn dealt(&mut self, game: Option<Game>, peers: &PlayerMap, _livepos: &mut LivePos) {
+        self.resetting = false;
+        let Some(game) = game else { return };
+        let bconf = game.board_conf;
+        self.pristine = game;
+        self.race_start = None;
+        self.ranked = false;
+        self.changed = true;
+        for r in self.racers.values_mut() {
+            r.game = self.pristine.clone();
+            r.moved = false;
+            r.dirty = false;
+            r.finished = None;
+        }
+        // everyone starts out on the same board
+        let board = Arc::new(board_message(&self.pristine.board));
+        peers.broadcast(Message::text(format!("bconf {bconf}")));
+        for p in peers.values() {
+            if let Err(e) = p.conn.tx.send_board(board.clone()) {
+                println!("couldn't send race board to {p}: {e}");
+            }
+        }
+        if let Some(lives) = self.pristine.lives {
+            peers.broadcast(Message::text(format!("lives {lives}")));
+        }
+    }
+
+    fn summary(&self) -> minesweeper::GameSummary {
+        // whoever's furthest along speaks for the room
+        let leader = self.racers.values().min_by_key(|r| r.game.board.hidden_tiles);
+        leader.map(|r| &r.game).unwrap_or(&self.pristine).summary()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroUsize;
+    use tokio::sync::mpsc as tokio_mpsc;
+    use minesweeper::{ BoardConf, FlagScope, Move, MoveType };
+
+    fn race() -> Race {
+        let board_conf = BoardConf {
+            w: NonZeroUsize::new(6).unwrap(), h: NonZeroUsize::new(6).unwrap(),
+            mine_ratio: (1, NonZeroUsize::new(6).unwrap()),
+            always_safe_first_move: false, revealed_borders: false, reveal_on_lose: false, num_tile_reveal: false,
+            flag_scope: FlagScope::Shared, fog_radius: None, hardcore_lives: None,
+        };
+        let conf = RoomConf {
+            name: "t".into(), mode: GameMode::Race, player_cap: NonZeroUsize::new(4).unwrap(), spectator_cap: 0,
+            public: false, board_conf, turn_limit: None, view_area: 100, password: None,
+        };
+        let (irc_tx, _) = tokio_mpsc::unbounded_channel();
+        let (lb_tx, _) = tokio_mpsc::unbounded_channel();
+        Race::new(irc_tx, lb_tx, conf)
+    }
+
+    /// What's waiting for `conn`, boards as just "board"
+    async fn drain(conn: &Conn) -> Vec<String> {
+        let mut got = vec![];
+        while let Ok(Some(m)) = tokio::time::timeout(std::time::Duration::from_millis(10), conn.tx.recv()).await {
+            got.push(m.to_str().map(|t| t.split(' ').next().unwrap().to_owned()).unwrap_or_else(|_| "board".into()));
+        }
+        got
+    }
+
+    #[tokio::test]
+    async fn boards_wait_for_the_frame_and_the_feed_for_a_change() {
+        let mut players = PlayerMap::new(false, false);
+        let conn = Conn::new(SocketAddr::from(([127, 0, 0, 1], 1)), 64);
+        players.insert_conn(conn.clone(), "p".into(), "#fff".into(), false);
+        let (mut race, mut lp) = (race(), LivePos::new(0));
+
+        race.handle(MetaMove::PlayersChanged, &players, &mut lp);
+        race.flush(&players, &mut lp);
+        assert_eq!(drain(&conn).await, ["progress"], "someone new in the race");
+
+        let flag = Move { t: MoveType::ToggleFlag, pos: (0, 0).try_into().unwrap() };
+        race.handle(MetaMove::Move(flag, conn.addr), &players, &mut lp);
+        race.handle(MetaMove::Move(flag, conn.addr), &players, &mut lp);
+        assert!(drain(&conn).await.is_empty(), "not before the frame");
+        race.flush(&players, &mut lp);
+        assert_eq!(drain(&conn).await, ["board"], "once, and flags don't move anyone up");
+
+        race.handle(MetaMove::StateDump, &players, &mut lp);
+        race.flush(&players, &mut lp);
+        assert_eq!(drain(&conn).await, ["bconf", "board"]);
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+};
+use tokio::time::{ Duration, Instant };
+use serde::Deserialize;
+
+// past this many tracked addresses, the ones that have fully recovered get forgotten,
+// at most once per PRUNE_EVERY so a flood of new addresses doesn't sweep the map on every call
+const PRUNE_AT: usize = 4096;
+const PRUNE_EVERY: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct RateConf {
+    /// how many can be done back to back
+    pub burst: f64,
+    /// how fast that allowance refills
+    pub per_sec: f64,
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+    conf: RateConf,
+}
+
+impl TokenBucket {
+    pub fn new(conf: RateConf) -> Self {
+        TokenBucket { tokens: conf.burst, last: Instant::now(), conf }
+    }
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.conf.per_sec).min(self.conf.burst);
+        self.last = now;
+    }
+    /// Spend a token, false if there's none left
+    pub fn take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else { false }
+    }
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.conf.burst
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    by_ip: HashMap<IpAddr, TokenBucket>,
+    pruned: Instant,
+}
+
+/// One bucket per client address
+#[derive(Debug)]
+pub struct IpLimiter {
+    buckets: Mutex<Buckets>,
+    conf: RateConf,
+}
+
+impl IpLimiter {
+    pub fn new(conf: RateConf) -> Self {
+        IpLimiter { buckets: Mutex::new(Buckets { by_ip: HashMap::new(), pruned: Instant::now() }), conf }
+    }
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.by_ip.len() >= PRUNE_AT && buckets.pruned.elapsed() >= PRUNE_EVERY {
+            buckets.by_ip.retain(|_, b| !b.is_full());
+            buckets.pruned = Instant::now();
+        }
+        buckets.by_ip.entry(ip).or_insert_with(|| TokenBucket::new(self.conf)).take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_refuses() {
+        let mut b = TokenBucket::new(RateConf { burst: 3.0, per_sec: 0.001 });
+        assert!(b.take() && b.take() && b.take());
+        assert!(!b.take());
+    }
+
+    #[test]
+    fn bucket_refills_up_to_burst() {
+        let mut b = TokenBucket::new(RateConf { burst: 2.0, per_sec: 1000.0 });
+        assert!(b.take() && b.take());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(b.is_full());
+        assert!(b.take() && b.take());
+    }
+
+    #[test]
+    fn limiter_keeps_addresses_apart() {
+        let l = IpLimiter::new(RateConf { burst: 1.0, per_sec: 0.001 });
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        assert!(l.check(a));
+        assert!(!l.check(a));
+        assert!(l.check(b));
+    }
+}
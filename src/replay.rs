@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    net::SocketAddr,
+};
+use serde::Serialize;
+use tokio::time::{ Instant, Duration };
+use crate::types::Player;
+use crate::minesweeper::{ Game, Move, MoveType, Phase };
+
+// Games in regular rooms get recorded as they're played: every move, and where
+// everyone's cursor was a few times a second, so a viewer can play back who was
+// looking where when it went wrong. The last few finished ones stay around to
+// be downloaded.
+
+/// least time between two points of someone's trail
+const SAMPLE: Duration = Duration::from_millis(100);
+/// most trail points in one recording, cursors stop being recorded past it
+const MAX_POINTS: usize = 1 << 18;
+/// finished games kept per room
+pub const KEPT: usize = 8;
+
+#[derive(Debug, Serialize)]
+struct Step {
+    /// milliseconds into the recording
+    t: u64,
+    uid: usize,
+    kind: &'static str,
+    x: isize,
+    y: isize,
+}
+
+#[derive(Debug, Serialize)]
+struct Trail {
+    uid: usize,
+    name: String,
+    /// `[ms, x, y]`, in livepos coordinates
+    points: Vec<(u64, u32, u32)>,
+    #[serde(skip)]
+    last: Option<Instant>,
+}
+
+/// One finished game, ready to go out as JSON
+#[derive(Debug, Serialize)]
+pub struct Replay {
+    pub id: usize,
+    /// `WxH n/d`, like `bconf`
+    pub bconf: String,
+    pub result: Phase,
+    /// milliseconds from the start of the recording to the last move
+    pub length: u64,
+    /// a bit per tile, set for mines, row by row and lowest bit first, deflated:
+    /// a list of offsets would run to megabytes on the biggest boards
+    mines: Vec<u8>,
+    moves: Vec<Step>,
+    trails: Vec<Trail>,
+}
+
+/// The game being played, as it's played
+#[derive(Debug)]
+pub struct Recorder {
+    since: Instant,
+    moves: Vec<Step>,
+    trails: HashMap<SocketAddr, Trail>,
+    points: usize,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder { since: Instant::now(), moves: vec![], trails: HashMap::new(), points: 0 }
+    }
+
+    fn now(&self) -> u64 {
+        self.since.elapsed().as_millis() as u64
+    }
+
+    /// Whether a cursor update from `addr` would make it into the trail, to skip looking them up otherwise
+    pub fn wants(&self, addr: &SocketAddr) -> bool {
+        self.points < MAX_POINTS && self.trails.get(addr)
+            .and_then(|t| t.last)
+            .map(|l| l.elapsed() >= SAMPLE)
+            .unwrap_or(true)
+    }
+
+    pub fn cursor(&mut self, p: &Player, pos: (u32, u32)) {
+        if !self.wants(&p.conn.addr) { return; }
+        let t = self.now();
+        let trail = self.trails.entry(p.conn.addr)
+            .or_insert_with(|| Trail { uid: p.uid, name: p.name.clone(), points: vec![], last: None });
+        trail.points.push((t, pos.0, pos.1));
+        trail.last = Some(Instant::now());
+        self.points += 1;
+    }
+
+    pub fn moved(&mut self, uid: usize, m: &Move) {
+        let (x, y): (isize, isize) = m.pos.try_into().unwrap();
+        let kind = match m.t {
+            MoveType::Reveal => "reveal",
+            MoveType::ToggleFlag => "flag",
+        };
+        self.moves.push(Step { t: self.now(), uid, kind, x, y });
+    }
+
+    /// Wrap it up, and start over for the next game
+    pub fn finish(&mut self, id: usize, game: &Game) -> Replay {
+        let done = std::mem::take(self);
+        Replay {
+            id,
+            bconf: game.board_conf.to_string(),
+            result: game.phase,
+            length: done.moves.last().map(|s| s.t).unwrap_or(0),
+            mines: mine_bits(game),
+            moves: done.moves,
+            trails: done.trails.into_values().collect(),
+        }
+    }
+}
+
+fn mine_bits(game: &Game) -> Vec<u8> {
+    use std::io::Write;
+    use flate2::{ Compression, write::DeflateEncoder };
+    let b = &game.board;
+    let mut bits = vec![0u8; b.area().div_ceil(8)];
+    for o in (0..b.area()).filter(|o| b.is_mine_at(*o)) {
+        bits[o / 8] |= 1 << (o % 8);
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&bits).unwrap();
+    encoder.finish().unwrap()
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::num::NonZeroUsize;
+    use crate::minesweeper::{ BoardConf, FlagScope };
+
+    #[test]
+    fn mines_come_back_out_of_the_bits() {
+        let conf = BoardConf {
+            w: NonZeroUsize::new(300).unwrap(), h: NonZeroUsize::new(200).unwrap(),
+            mine_ratio: (1, NonZeroUsize::new(6).unwrap()),
+            always_safe_first_move: false, revealed_borders: false, reveal_on_lose: false,
+            num_tile_reveal: false, flag_scope: FlagScope::Shared, fog_radius: None, hardcore_lives: None,
+        };
+        let game = Game::new(conf);
+        let replay = Recorder::new().finish(1, &game);
+        assert!(replay.mines.len() < game.board.area() / 8);
+        let mut bits = vec![];
+        flate2::read::DeflateDecoder::new(&replay.mines[..]).read_to_end(&mut bits).unwrap();
+        assert_eq!(bits.len(), game.board.area() / 8);
+        assert!((0..game.board.area()).all(|o| (bits[o / 8] >> (o % 8) & 1 == 1) == game.board.is_mine_at(o)));
+    }
+}
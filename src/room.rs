@@ -0,0 +1,373 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio::sync::mpsc as tokio_mpsc;
+use tokio::time::{ self, Instant, Duration };
+use warp::ws::Message;
+use crate::types::*;
+use crate::livepos::LivePos;
+use crate::minesweeper::{ Game, GameSummary };
+use crate::replay::Replay;
+
+// A room is one task. It owns the players, their cursors and the game, and
+// everything that wants anything from it sends a `RoomMsg` and, if it needs an
+// answer, waits on a oneshot. Nothing in here is shared, so nothing is locked.
+// Moves are applied as they come, but the game only gets to tell everyone
+// about them once per frame, however many came in meanwhile.
+
+/// most messages taken in before the game gets to tell everyone what changed
+const BATCH: usize = 256;
+
+/// A game being laid out on the blocking pool, see `Mode::take_dealing`
+pub type Dealing = tokio::task::JoinHandle<Game>;
+
+/// Lay out a game on the blocking pool, big boards take a while and the room has everyone else to see to
+pub fn deal(make: impl FnOnce() -> Game + Send + 'static) -> Dealing {
+    tokio::task::spawn_blocking(make)
+}
+
+/// How a game's played, driven by its room
+pub trait Mode: Send {
+    fn handle(&mut self, req: MetaMove, peers: &PlayerMap, livepos: &mut LivePos);
+    /// Tell everyone what changed since last time, after a batch of messages
+    fn flush(&mut self, _peers: &PlayerMap, _livepos: &mut LivePos) {}
+    /// When `expire` wants to be called, if ever
+    fn deadline(&self) -> Option<Instant> { None }
+    fn expire(&mut self, _peers: &PlayerMap, _livepos: &mut LivePos) {}
+    /// Whether cursor moves from `addr` are any use to the game, fogged boards and replays need them
+    fn wants_cursor(&self, _addr: &SocketAddr) -> bool { false }
+    /// Whether everyone's cursor gets passed around to everyone else
+    fn shares_cursors(&self) -> bool { true }
+    /// Whether it deals its next board with the host's settings, see `MetaMove::Reconf`
+    fn takes_settings(&self) -> bool { true }
+    fn summary(&self) -> GameSummary;
+    fn replays(&self) -> Vec<Arc<Replay>> { vec![] }
+    /// A game that started being dealt, handed over once for the room to wait on
+    fn take_dealing(&mut self) -> Option<Dealing> { None }
+    /// What was being dealt, none if dealing it went wrong
+    fn dealt(&mut self, _game: Option<Game>, _peers: &PlayerMap, _livepos: &mut LivePos) {}
+}
+
+pub struct RoomTask {
+    id: RoomId,
+    conf: RoomConf,
+    players: PlayerMap,
+    livepos: LivePos,
+    mode: Box<dyn Mode>,
+    /// least time between two flushes
+    frame: Duration,
+    /// no flush before this
+    next_frame: Instant,
+    /// something came in since the last flush
+    pending: bool,
+    /// the next game, while it's being dealt
+    dealing: Option<Dealing>,
+}
+
+impl RoomTask {
+    /// `max_fps` caps how often the game tells everyone what changed, 0 for as soon as it can
+    pub fn new(id: RoomId, conf: RoomConf, players: PlayerMap, livepos: LivePos, mode: Box<dyn Mode>, max_fps: u32) -> Self {
+        let frame = if max_fps == 0 { Duration::ZERO } else { Duration::from_secs(1) / max_fps };
+        RoomTask { id, conf, players, livepos, mode, frame, next_frame: Instant::now(), pending: false, dealing: None }
+    }
+
+    pub async fn run(mut self, mut rx: tokio_mpsc::UnboundedReceiver<RoomMsg>) {
+        loop {
+            let deadline = self.mode.deadline();
+            let tick = self.livepos.due();
+            let frame = self.pending.then_some(self.next_frame);
+            tokio::select! {
+                msg = rx.recv() => {
+                    let Some(msg) = msg else { break };
+                    self.on(msg);
+                    // whatever piled up meanwhile goes in before anyone hears about it
+                    for _ in 1..BATCH {
+                        let Ok(msg) = rx.try_recv() else { break };
+                        self.on(msg);
+                    }
+                    self.pending = true;
+                    self.flush();
+                },
+                _ = time::sleep_until(tick.unwrap_or_else(Instant::now)), if tick.is_some() => {
+                    self.livepos.tick(&self.players);
+                },
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.mode.expire(&self.players, &mut self.livepos);
+                    self.pending = true;
+                    self.flush();
+                },
+                _ = time::sleep_until(frame.unwrap_or_else(Instant::now)), if frame.is_some() => self.flush(),
+                dealt = async { self.dealing.as_mut().unwrap().await }, if self.dealing.is_some() => {
+                    self.dealing = None;
+                    let game = dealt.map_err(|e| println!("{} E: dealing the next game failed: {e}", self.id)).ok();
+                    if let Some(g) = &game { self.conf.board_conf = g.board_conf; }
+                    self.mode.dealt(game, &self.players, &mut self.livepos);
+                    self.pending = true;
+                    self.flush();
+                },
+            }
+        }
+    }
+
+    /// Let the game catch everyone up, unless it did so less than a frame ago
+    fn flush(&mut self) {
+        let now = Instant::now();
+        if now < self.next_frame { return; }
+        self.mode.flush(&self.players, &mut self.livepos);
+        self.pending = false;
+        self.next_frame = now + self.frame;
+    }
+
+    fn game(&mut self, req: MetaMove) {
+        self.mode.handle(req, &self.players, &mut self.livepos);
+        if let Some(d) = self.mode.take_dealing() {
+            self.dealing = Some(d);
+        }
+    }
+
+    fn on(&mut self, msg: RoomMsg) {
+        let room_id = &self.id;
+        match msg {
+            RoomMsg::Join { conn, name, clr, spectate } => self.join(conn, name, clr, spectate),
+            RoomMsg::Leave(addr) => self.leave(&addr),
+            RoomMsg::Cmd(addr, cmd) => self.command(&addr, cmd),
+            RoomMsg::Info(reply_tx) => {
+                let _ = reply_tx.send(RoomInfo {
+                    players: self.players.player_count(),
+                    spectators: self.players.spectator_count(),
+                    summary: self.mode.summary(),
+                });
+            },
+            RoomMsg::Replays(reply_tx) => {
+                if reply_tx.send(self.mode.replays()).is_err() {
+                    println!("{room_id} E: nobody waited for the replays");
+                }
+            },
+        }
+    }
+
+    fn join(&mut self, conn: Conn, name: String, clr: String, spectate: bool) {
+        let room_id = &self.id;
+        let players = &mut self.players;
+        let spectator = if !spectate && players.player_count() < self.conf.player_cap.get() {
+            false
+        } else if players.spectator_count() < self.conf.spectator_cap {
+            true
+        } else {
+            println!("{room_id} I: turned \"{name}@{}\" away, room is full", conn.addr);
+            if let Err(e) = conn.tx.send(Message::text("err room is full")) {
+                println!("{room_id} E: couldn't report full room to {}: {e}", conn.addr);
+            }
+            conn.tx.close();
+            return
+        };
+        let role = if spectator { "spectator" } else { "player" };
+        println!("{room_id} I: registered \"{name}@{}\" as {role}", conn.addr);
+        let uid = players.insert_conn(conn.clone(), name.clone(), clr, spectator);
+        if let Err(e) = conn.tx.send(Message::text(format!("regack {} {} {} {} {role}",
+                                                    self.conf.name.replace(' ', "&nbsp;"), name.replace(' ', "&nbsp;"), uid, self.conf.board_conf))) {
+            println!("{room_id} E: couldn't send register ack to {}: {e}", conn.addr);
+        }
+        if spectator {
+            // a new spectator changes nothing for the others
+            if let Err(e) = conn.tx.send(players_msg(players)) {
+                println!("{room_id} E: couldn't dump players for {}: {e}", conn.addr);
+            }
+        } else {
+            broadcast_players(players);
+        }
+        self.livepos.dump();
+        self.game(MetaMove::StateDump);
+    }
+
+    fn leave(&mut self, addr: &SocketAddr) {
+        let room_id = &self.id;
+        let Some((gone, host_changed)) = self.players.remove_conn(addr) else {
+            println!("{room_id} I: {addr} disconnected");
+            return
+        };
+        self.livepos.quit(gone.uid);
+        if !gone.spectator {
+            self.players.broadcast(Message::text(format!("logoff {}", gone.uid)));
+        }
+        if host_changed {
+            if let Some(h) = self.players.host.and_then(|h| self.players.get(&h)) {
+                println!("{room_id} I: {h} is the new host");
+            }
+            broadcast_players(&self.players);
+        }
+        println!("{room_id} I: {gone} disconnected");
+        if !gone.spectator {
+            self.game(MetaMove::PlayersChanged);
+        }
+    }
+
+    fn command(&mut self, addr: &SocketAddr, cmd: Command) {
+        let room_id = &self.id;
+        let Some(me) = self.players.get(addr) else { return };
+        let is_host = self.players.is_host(addr);
+        match cmd {
+            Command::Pos(_) if me.spectator => (), // nobody gets to see where spectators look
+            Command::Pos(pos) => {
+                if self.mode.shares_cursors() {
+                    self.livepos.moved(me.uid, pos);
+                }
+                if self.mode.wants_cursor(addr) {
+                    self.game(MetaMove::Cursor(*addr, pos));
+                }
+            },
+            Command::View(view) => self.game(MetaMove::View(*addr, view)),
+            Command::Move(_) | Command::Reset if me.spectator => reject(room_id, me, "spectators can't play"),
+            Command::Move(m) => self.game(MetaMove::Move(m, *addr)),
+            Command::Reset => {
+                if self.players.host_only_reset && !is_host {
+                    reject(room_id, me, "only the host can reset");
+                } else {
+                    self.game(MetaMove::Reset);
+                }
+            },
+            Command::Kick(_) | Command::HostReset(_) | Command::Settings(_) if !is_host => {
+                reject(room_id, me, "only the host can do that");
+            },
+            Command::Kick(uid) => {
+                if let Some(target) = self.players.by_uid(uid).filter(|p| p.conn.addr != *addr) {
+                    println!("{room_id} I: host kicked {target}");
+                    let _ = target.conn.tx.send(Message::text("err you were kicked by the host"));
+                    target.conn.tx.close();
+                }
+            },
+            Command::HostReset(on) => {
+                self.players.host_only_reset = on;
+                broadcast_players(&self.players);
+            },
+            Command::Settings(_) if !self.mode.takes_settings() => reject(room_id, me, "this room's board can't be changed"),
+            Command::Settings(bconf) => {
+                // the board in play stays what newcomers hear about until the next one's dealt
+                self.players.broadcast(Message::text(format!("nextconf {bconf}")));
+                self.game(MetaMove::Reconf(bconf));
+            },
+        }
+    }
+}
+
+fn reject(room_id: &RoomId, p: &Player, why: &str) {
+    if let Err(e) = p.conn.tx.send(Message::text(format!("err {why}"))) {
+        println!("{room_id} E: couldn't tell {p} \"{why}\": {e}");
+    }
+}
+
+fn players_msg(players: &PlayerMap) -> Message {
+    Message::text(format!("players {}", jsonenc_players(players).expect("couldn't JSONify players")))
+}
+
+fn broadcast_players(players: &PlayerMap) {
+    players.broadcast(players_msg(players));
+}
+
+fn jsonenc_players(players: &PlayerMap) -> Result<String, serde_json::Error> {
+    let mut pairs = Vec::new();
+    for player in players.values().filter(|p| !p.spectator) {
+        pairs.push((player.uid, player.name.replace(' ', "&nbsp"), player.clr.clone(), player.team));
+    }
+    serde_json::to_string(&serde_json::json!({
+        "host": players.host_uid(),
+        "hostreset": players.host_only_reset,
+        "list": pairs,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroUsize;
+    use crate::minesweeper::{ BoardConf, FlagScope, Move, MoveType };
+
+    /// Every tile's a mine, the first dig loses
+    fn mined(side: usize) -> BoardConf {
+        BoardConf {
+            w: NonZeroUsize::new(side).unwrap(), h: NonZeroUsize::new(side).unwrap(),
+            mine_ratio: (1, NonZeroUsize::new(1).unwrap()),
+            always_safe_first_move: false, revealed_borders: false, reveal_on_lose: false, num_tile_reveal: false,
+            flag_scope: FlagScope::Shared, fog_radius: None, hardcore_lives: None,
+        }
+    }
+
+    type Reports = (tokio_mpsc::UnboundedReceiver<crate::ircbot::IrcCmd>, tokio_mpsc::UnboundedReceiver<crate::leaderboard::LbCmd>);
+
+    /// A coop room's queue, and the ones it reports to, kept so it has somewhere to report
+    fn coop_room(board_conf: BoardConf) -> (CmdTx, Reports) {
+        let conf = RoomConf {
+            name: "t".into(), mode: GameMode::Coop, player_cap: NonZeroUsize::new(4).unwrap(), spectator_cap: 0,
+            public: false, board_conf, turn_limit: None, view_area: 100, password: None,
+        };
+        let (irc_tx, irc_rx) = tokio_mpsc::unbounded_channel();
+        let (lb_tx, lb_rx) = tokio_mpsc::unbounded_channel();
+        let game = Box::new(crate::gameloop::SharedGame::new(irc_tx, lb_tx, conf.clone()));
+        let (tx, rx) = tokio_mpsc::unbounded_channel();
+        tokio::spawn(RoomTask::new(RoomId("t".into()), conf, PlayerMap::new(false, false), LivePos::new(0), game, 0).run(rx));
+        (tx, (irc_rx, lb_rx))
+    }
+
+    fn join(tx: &CmdTx, port: u16) -> Conn {
+        let conn = Conn::new(SocketAddr::from(([127, 0, 0, 1], port)), 64);
+        tx.send(RoomMsg::Join { conn: conn.clone(), name: "p".into(), clr: "#fff".into(), spectate: false }).unwrap();
+        conn
+    }
+
+    async fn next(conn: &Conn) -> Message {
+        tokio::time::timeout(Duration::from_secs(5), conn.tx.recv()).await.expect("room went quiet").unwrap()
+    }
+
+    async fn next_text(conn: &Conn, prefix: &str) -> String {
+        loop {
+            if let Ok(t) = next(conn).await.to_str() {
+                if t.starts_with(prefix) { return t.to_owned(); }
+            }
+        }
+    }
+
+    async fn lose(tx: &CmdTx, conn: &Conn) {
+        let pos = (0u32, 0u32).try_into().unwrap();
+        tx.send(RoomMsg::Cmd(conn.addr, Command::Move(Move { t: MoveType::Reveal, pos }))).unwrap();
+        next_text(conn, "lose ").await;
+    }
+
+    #[tokio::test]
+    async fn resets_deal_the_next_game_off_the_room() {
+        let (tx, _reports) = coop_room(mined(4));
+        let conn = join(&tx, 1);
+        lose(&tx, &conn).await;
+
+        tx.send(RoomMsg::Cmd(conn.addr, Command::Reset)).unwrap();
+        // a fresh board, everything hidden again
+        loop {
+            let m = next(&conn).await;
+            if m.is_binary() {
+                use std::io::Read;
+                let mut board = vec![];
+                flate2::read::DeflateDecoder::new(m.as_bytes()).read_to_end(&mut board).unwrap();
+                assert_eq!(board, b"####<br>####<br>####<br>####<br>");
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn newcomers_hear_of_new_settings_once_they_are_dealt() {
+        let (tx, _reports) = coop_room(mined(4));
+        let host = join(&tx, 1);
+        next_text(&host, "regack ").await;
+        tx.send(RoomMsg::Cmd(host.addr, Command::Settings(mined(5)))).unwrap();
+        assert_eq!(next_text(&host, "nextconf ").await, "nextconf 5x5 1/1");
+
+        let early = join(&tx, 2);
+        assert!(next_text(&early, "regack ").await.ends_with(" 4x4 1/1 player"), "the board in play");
+
+        lose(&tx, &host).await;
+        tx.send(RoomMsg::Cmd(host.addr, Command::Reset)).unwrap();
+        next_text(&host, "bconf 5x5").await;
+        let late = join(&tx, 3);
+        assert!(next_text(&late, "regack ").await.ends_with(" 5x5 1/1 player"));
+    }
+}
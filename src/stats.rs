@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use serde::Serialize;
+use crate::minesweeper::{ Board, Move, MoveType, MoveOutcome, FlagOwner };
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PlayerStats {
+    pub uid: usize,
+    pub name: String,
+    pub revealed: usize,
+    pub flags_correct: usize,
+    pub flags_wrong: usize,
+    pub explosions: usize,
+}
+
+/// Who did what during a single game
+#[derive(Debug, Default)]
+pub struct Scoreboard {
+    players: HashMap<usize, PlayerStats>,
+    /// board offset and flag layer -> uid of whoever planted the flag sitting there
+    flag_owners: HashMap<(usize, Option<FlagOwner>), usize>,
+}
+
+impl Scoreboard {
+    /// `layer` is where the mover's flags go, see `Game::flag_owner`
+    pub fn record(&mut self, uid: usize, name: &str, m: &Move, outcome: &MoveOutcome, board: &Board, layer: Option<FlagOwner>) {
+        let entry = self.players.entry(uid).or_insert_with(|| PlayerStats { uid, ..Default::default() });
+        entry.name = name.to_string();
+        entry.revealed += outcome.revealed;
+        if outcome.exploded { entry.explosions += 1; }
+        if outcome.wrong_flag { entry.flags_wrong += 1; }
+        if m.t == MoveType::ToggleFlag {
+            if let Some(off) = m.pos.rel_offset(board) {
+                if outcome.flag_set {
+                    self.flag_owners.insert((off, layer), uid);
+                } else {
+                    self.flag_owners.remove(&(off, layer));
+                }
+            }
+        }
+    }
+
+    /// Settle the flags left on the board, call before `Board::grade` clears them
+    pub fn grade(&mut self, board: &Board) {
+        for ((off, _), uid) in self.flag_owners.drain() {
+            if let Some(p) = self.players.get_mut(&uid) {
+                if board.is_mine_at(off) { p.flags_correct += 1; } else { p.flags_wrong += 1; }
+            }
+        }
+    }
+
+    /// Everyone who played, most helpful first
+    pub fn names(&self) -> Vec<String> {
+        self.ranked().into_iter().map(|p| p.name.clone()).collect()
+    }
+
+    fn ranked(&self) -> Vec<&PlayerStats> {
+        let mut rows = self.players.values().collect::<Vec<_>>();
+        rows.sort_by_key(|p| (std::cmp::Reverse(p.revealed + p.flags_correct), p.uid));
+        rows
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&self.ranked())
+    }
+}
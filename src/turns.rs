@@ -0,0 +1,151 @@
+use std::net::SocketAddr;
+use tokio::time::{ Instant, Duration };
+use warp::ws::Message;
+use crate::types::{ PlayerMap, Player };
+use crate::minesweeper::Team;
+
+// Turn-based rooms: players take turns by uid, wrapping around, and whoever
+// sits on their turn past the time limit gets skipped. With teams, the turn
+// goes back and forth between them, each team going through its own players.
+
+#[derive(Debug)]
+pub struct TurnOrder {
+    /// uid, address and team of whoever's on the clock
+    current: Option<(usize, SocketAddr, Option<Team>)>,
+    /// uid of each team's last player on the clock
+    last_of: [Option<usize>; 2],
+    /// when they get skipped, none while there's no limit or no game going
+    deadline: Option<Instant>,
+    limit: Option<Duration>,
+}
+
+impl TurnOrder {
+    pub fn new(limit: Option<Duration>) -> Self {
+        TurnOrder { current: None, last_of: [None; 2], deadline: None, limit }
+    }
+    pub fn is_turn(&self, addr: &SocketAddr) -> bool {
+        self.current.map(|(_, a, _)| a == *addr).unwrap_or(false)
+    }
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+    /// Hand the turn to the next player by uid after `after`, wrapping around,
+    /// or to the next one on the other team if `from` had one and it isn't empty
+    fn pass_from(&mut self, players: &PlayerMap, after: Option<usize>, from: Option<Team>) {
+        let playing = || players.values().filter(|p| !p.spectator);
+        let other = from.map(Team::other).filter(|t| playing().any(|p| p.team == Some(*t)));
+        let after = match other {
+            Some(t) => self.last_of[t.index()],
+            None => after,
+        };
+        let in_line = |p: &&Player| other.map(|t| p.team == Some(t)).unwrap_or(true);
+        let next = playing().filter(in_line)
+            .filter(|p| after.map(|a| p.uid > a).unwrap_or(true))
+            .min_by_key(|p| p.uid)
+            .or_else(|| playing().filter(in_line).min_by_key(|p| p.uid));
+        self.current = next.map(|p| (p.uid, p.conn.addr, p.team));
+        if let Some(t) = next.and_then(|p| p.team) {
+            self.last_of[t.index()] = next.map(|p| p.uid);
+        }
+        self.restart_clock();
+    }
+    pub fn advance(&mut self, players: &PlayerMap) {
+        let (after, from) = self.current.map(|(uid, _, team)| (Some(uid), team)).unwrap_or((None, None));
+        self.pass_from(players, after, from);
+    }
+    /// Keep the turn on someone who's still around, true if it moved
+    pub fn sync(&mut self, players: &PlayerMap) -> bool {
+        match self.current {
+            Some((_, addr, _)) if players.get(&addr).map(|p| !p.spectator).unwrap_or(false) => false,
+            // whoever was on the clock left, the one after them goes
+            Some((uid, _, team)) => { self.pass_from(players, Some(uid), team); true },
+            None => { self.pass_from(players, None, None); self.current.is_some() },
+        }
+    }
+    /// Stop the clock, for when there's no game to play
+    pub fn pause(&mut self) {
+        self.deadline = None;
+    }
+    pub fn restart_clock(&mut self) {
+        self.deadline = self.current.and(self.limit).map(|l| Instant::now() + l);
+    }
+    /// `turn <uid> <seconds left>`, with a dash for no limit and no uid while nobody's playing
+    pub fn message(&self) -> Message {
+        let uid = self.current.map(|(uid, _, _)| uid.to_string()).unwrap_or_else(|| "-".to_string());
+        let left = self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()).as_secs().to_string())
+            .unwrap_or_else(|| "-".to_string());
+        Message::text(format!("turn {uid} {left}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Conn;
+
+    fn join(players: &mut PlayerMap, port: u16, spectator: bool) -> usize {
+        let conn = Conn::new(SocketAddr::from(([127, 0, 0, 1], port)), 8);
+        players.insert_conn(conn, format!("p{port}"), "#fff".into(), spectator)
+    }
+    fn on_turn(t: &TurnOrder) -> Option<usize> {
+        t.current.map(|(uid, _, _)| uid)
+    }
+
+    #[test]
+    fn turns_go_by_uid_and_wrap() {
+        let mut players = PlayerMap::new(false, false);
+        let a = join(&mut players, 1, false);
+        join(&mut players, 2, true);
+        let c = join(&mut players, 3, false);
+        let mut t = TurnOrder::new(None);
+        assert!(t.sync(&players));
+        assert_eq!(on_turn(&t), Some(a));
+        t.advance(&players);
+        assert_eq!(on_turn(&t), Some(c), "spectators don't get a turn");
+        t.advance(&players);
+        assert_eq!(on_turn(&t), Some(a));
+    }
+
+    #[test]
+    fn turn_passes_on_when_its_holder_leaves() {
+        let mut players = PlayerMap::new(false, false);
+        let a = join(&mut players, 1, false);
+        let b = join(&mut players, 2, false);
+        let mut t = TurnOrder::new(None);
+        t.sync(&players);
+        assert!(!t.sync(&players));
+        players.remove_conn(&SocketAddr::from(([127, 0, 0, 1], 1)));
+        assert!(t.sync(&players));
+        assert_eq!(on_turn(&t), Some(b));
+        assert_ne!(on_turn(&t), Some(a));
+    }
+
+    #[test]
+    fn teams_take_turns_back_and_forth() {
+        let mut players = PlayerMap::new(false, true);
+        let a1 = join(&mut players, 1, false);
+        let b1 = join(&mut players, 2, false);
+        let a2 = join(&mut players, 3, false);
+        let b2 = join(&mut players, 4, false);
+        let mut t = TurnOrder::new(None);
+        t.sync(&players);
+        let order = std::iter::once(on_turn(&t)).chain((0..4).map(|_| { t.advance(&players); on_turn(&t) }))
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![Some(a1), Some(b1), Some(a2), Some(b2), Some(a1)]);
+    }
+
+    #[test]
+    fn clock_only_runs_with_a_limit() {
+        let mut players = PlayerMap::new(false, false);
+        join(&mut players, 1, false);
+        let mut t = TurnOrder::new(None);
+        t.sync(&players);
+        assert!(t.deadline().is_none());
+        let mut t = TurnOrder::new(Some(Duration::from_secs(5)));
+        t.sync(&players);
+        assert!(t.deadline().is_some());
+        t.pause();
+        assert!(t.deadline().is_none());
+    }
+}
@@ -7,46 +7,194 @@ use std::{
 },
     fmt::Display,
     ops::{ Deref, DerefMut },
-    num::NonZeroUsize,
+    num::{ NonZeroU32, NonZeroUsize },
 };
-use warp::ws::Message;
-use tokio::sync::RwLock;
+use ring::pbkdf2;
+use tokio::sync::oneshot;
+use tokio::time::{ Instant, Duration };
 use serde::Serialize;
 use crate::minesweeper;
-use crate::livepos;
 use crate::ircbot;
+use crate::outbox::{ Frame, Outbox };
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub enum GameMode {
+    /// everyone on one board
+    Coop,
+    /// everyone on their own copy of the same board
+    Race,
+    /// one shared board, players take turns digging
+    Turns,
+    /// Minesweeper Flags, two teams take turns digging for mines
+    Flags,
+    /// a world without edges, everyone looking at their own part of it
+    Endless,
+}
+
+impl GameMode {
+    pub fn from_form(s: Option<&String>) -> Option<Self> {
+        match s.map(|s| s.as_str()) {
+            None | Some("coop") => Some(GameMode::Coop),
+            Some("race") => Some(GameMode::Race),
+            Some("turns") => Some(GameMode::Turns),
+            Some("flags") => Some(GameMode::Flags),
+            Some("endless") => Some(GameMode::Endless),
+            Some(_) => None,
+        }
+    }
+    /// Largest board the mode takes: races send boards out whole and endless
+    /// rooms take the board's size as the view's, so both stay within a view
+    pub fn max_area(self, board_area: usize, view_area: usize) -> usize {
+        match self {
+            GameMode::Race | GameMode::Endless => view_area.min(board_area),
+            _ => board_area,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Clone)]
 pub struct RoomConf {
     pub name: String,
+    pub mode: GameMode,
     pub player_cap: NonZeroUsize,
+    pub spectator_cap: usize,
     pub public: bool,
     pub board_conf: minesweeper::BoardConf,
+    /// seconds a player gets per turn in turn-based rooms before being skipped
+    pub turn_limit: Option<u64>,
+    /// most tiles anyone gets sent at once, bigger boards go out in views
+    #[serde(skip)]
+    pub view_area: usize,
+    /// the lobby only gets to know whether there is one
+    #[serde(rename = "locked", serialize_with = "RoomPassword::serialize_is_set")]
+    pub password: Option<RoomPassword>,
+}
+
+/// A room's password, salted and stretched with PBKDF2, the plaintext is never kept.
+/// Making and checking one is slow on purpose, so it's done off the async threads
+#[derive(Clone)]
+pub struct RoomPassword {
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+// PBKDF2-HMAC-SHA256 rounds: ~80ms a try in a release build, fine for one player and slow going for a guesser
+const PASSWORD_ROUNDS: u32 = 100_000;
+
+impl RoomPassword {
+    pub fn new(plain: &str) -> Self {
+        let salt = rand::random::<[u8; 16]>();
+        let mut hash = [0; 32];
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, Self::rounds(), &salt, plain.as_bytes(), &mut hash);
+        RoomPassword { salt, hash }
+    }
+    fn rounds() -> NonZeroU32 {
+        NonZeroU32::new(PASSWORD_ROUNDS).unwrap()
+    }
+    /// Compared in constant time, so timing doesn't tell how close a guess was
+    pub fn matches(&self, plain: &str) -> bool {
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, Self::rounds(), &self.salt, plain.as_bytes(), &self.hash).is_ok()
+    }
+    /// `matches` on the blocking pool
+    pub async fn check(&self, plain: &str) -> bool {
+        let (pw, plain) = (self.clone(), plain.to_owned());
+        tokio::task::spawn_blocking(move || pw.matches(&plain)).await.unwrap_or(false)
+    }
+    fn serialize_is_set<S: serde::Serializer>(pw: &Option<RoomPassword>, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_bool(pw.is_some())
+    }
+}
+impl std::fmt::Debug for RoomPassword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RoomPassword(..)")
+    }
 }
 
+/// A room as the rest of the server sees it, everything in it belongs to its task
 pub struct Room {
+    /// how it was set up, the task keeps the current board settings
     pub conf: RoomConf,
-    pub players: Arc<RwLock<PlayerMap>>,
-    pub game_driver: tokio::task::JoinHandle<()>,
     pub cmd_stream: CmdTx,
-    pub livepos_driver: tokio::task::JoinHandle<()>,
-    pub pos_stream: tokio::sync::mpsc::UnboundedSender<livepos::Req>,
+    pub driver: tokio::task::JoinHandle<()>,
     pub irc_driver: tokio::task::JoinHandle<()>,
     pub irc_stream: tokio::sync::mpsc::UnboundedSender<ircbot::IrcCmd>,
 }
 
+impl Drop for Room {
+    fn drop(&mut self) {
+        // the drivers hold senders to their own queues, so they'd never finish on their own
+        self.driver.abort();
+        self.irc_driver.abort();
+    }
+}
+
+/// What a room's task gets asked, by connections and the lobby
+#[derive(Debug)]
+pub enum RoomMsg {
+    /// someone who got past the door, to play or just watch
+    Join { conn: Conn, name: String, clr: String, spectate: bool },
+    Leave(SocketAddr),
+    Cmd(SocketAddr, Command),
+    /// head count and how the game's going
+    Info(oneshot::Sender<RoomInfo>),
+    /// the recordings of the last few games, oldest first
+    Replays(oneshot::Sender<Vec<Arc<crate::replay::Replay>>>),
+}
+
+/// Something a player or spectator did, already parsed
+#[derive(Debug)]
+pub enum Command {
+    Pos((u32, u32)),
+    View(crate::world::View),
+    Move(minesweeper::Move),
+    Reset,
+    Kick(usize),
+    HostReset(bool),
+    Settings(minesweeper::BoardConf),
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RoomInfo {
+    pub players: usize,
+    pub spectators: usize,
+    pub summary: minesweeper::GameSummary,
+}
+
+/// What game modes get told by their room
 #[derive(Debug)]
 pub enum MetaMove {
     Move(minesweeper::Move,SocketAddr),
+    /// everything to everyone, someone new came in
     StateDump,
-    StateSync,
     Reset,
+    /// board configuration to switch to on the next reset
+    Reconf(minesweeper::BoardConf),
+    /// someone joined or left
+    PlayersChanged,
+    /// where a player's cursor is, in livepos coordinates, for fogged boards
+    Cursor(SocketAddr, (u32, u32)),
+    /// what part of an endless world someone's looking at
+    View(SocketAddr, crate::world::View),
 }
 
 #[derive(Debug, Clone)]
 pub struct Conn {
-    pub tx: tokio::sync::mpsc::UnboundedSender<Message>,
+    pub tx: Arc<Outbox>,
     pub addr: SocketAddr,
+    pub last_seen: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl Conn {
+    pub fn new(addr: SocketAddr, out_cap: usize) -> Self {
+        Conn { tx: Arc::new(Outbox::new(out_cap)), addr, last_seen: Arc::new(std::sync::Mutex::new(Instant::now())) }
+    }
+    /// mark the connection as alive, any inbound frame counts
+    pub fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+    pub fn idle_for(&self) -> Duration {
+        self.last_seen.lock().unwrap().elapsed()
+    }
 }
 
 #[derive(Debug)]
@@ -55,6 +203,16 @@ pub struct Player {
     pub uid: usize,
     pub name: String,
     pub clr: String,
+    /// sees everything, touches nothing
+    pub spectator: bool,
+    /// side they're on, in team games
+    pub team: Option<minesweeper::Team>,
+}
+
+impl Player {
+    pub fn actor(&self) -> minesweeper::Actor {
+        minesweeper::Actor { uid: Some(self.uid), team: self.team }
+    }
 }
 
 impl Display for Player {
@@ -94,13 +252,19 @@ impl RoomId {
     }
 }
 
-pub type CmdTx = tokio::sync::mpsc::UnboundedSender<MetaMove>;
-pub type RoomMap = HashMap<RoomId, Arc<RwLock<Room>>>;
+pub type CmdTx = tokio::sync::mpsc::UnboundedSender<RoomMsg>;
+pub type RoomMap = HashMap<RoomId, Arc<Room>>;
 pub type PlayerMapData = HashMap<SocketAddr, Player>;
 #[derive(Debug)]
 pub struct PlayerMap {
     inner: PlayerMapData,
     uid_counter: AtomicUsize,
+    /// the player in charge, the first one in and then whoever's been around the longest
+    pub host: Option<SocketAddr>,
+    /// whether resetting is one of the host's privileges, the host can toggle it
+    pub host_only_reset: bool,
+    /// whether players get split into teams as they join
+    pub teams: bool,
 }
 
 impl Deref for PlayerMap {
@@ -114,20 +278,76 @@ impl DerefMut for PlayerMap {
         &mut self.inner
     }
 }
-impl Default for PlayerMap {
-    fn default() -> Self {
-        Self { inner: HashMap::new(), uid_counter: 0.into() }
-    }
-}
 
 impl PlayerMap {
-    pub fn insert_conn(&mut self, conn: Conn, name: String, clr: String) -> usize {
+    pub fn new(host_only_reset: bool, teams: bool) -> Self {
+        Self { inner: HashMap::new(), uid_counter: 0.into(), host: None, host_only_reset, teams }
+    }
+    pub fn insert_conn(&mut self, conn: Conn, name: String, clr: String, spectator: bool) -> usize {
         let uid = self.uid_counter.fetch_add(1, Ordering::Relaxed);
+        if self.host.is_none() && !spectator {
+            self.host = Some(conn.addr);
+        }
+        // newcomers even the teams out
+        let team = (self.teams && !spectator).then(|| {
+            use minesweeper::Team;
+            let on = |t| self.values().filter(|p| p.team == Some(t)).count();
+            if on(Team::B) < on(Team::A) { Team::B } else { Team::A }
+        });
         self.insert(
             conn.addr,
-            Player { conn, uid, name, clr },
+            Player { conn, uid, name, clr, spectator, team },
         );
         uid
     }
+    /// Take a player out, handing the host role over if they held it.
+    /// The bool tells whether the host changed.
+    pub fn remove_conn(&mut self, addr: &SocketAddr) -> Option<(Player, bool)> {
+        let p = self.remove(addr)?;
+        let host_left = self.host == Some(*addr);
+        if host_left {
+            self.host = self.values()
+                .filter(|p| !p.spectator)
+                .min_by_key(|p| p.uid)
+                .map(|p| p.conn.addr);
+        }
+        Some((p, host_left))
+    }
+    pub fn is_host(&self, addr: &SocketAddr) -> bool {
+        self.host.as_ref() == Some(addr)
+    }
+    pub fn host_uid(&self) -> Option<usize> {
+        self.host.and_then(|h| self.get(&h)).map(|p| p.uid)
+    }
+    pub fn by_uid(&self, uid: usize) -> Option<&Player> {
+        self.values().find(|p| p.uid == uid)
+    }
+    pub fn player_count(&self) -> usize {
+        self.values().filter(|p| !p.spectator).count()
+    }
+    pub fn spectator_count(&self) -> usize {
+        self.values().filter(|p| p.spectator).count()
+    }
+    /// Queue `m` for everyone, made once and shared between them
+    pub fn broadcast(&self, m: impl Into<Frame>) {
+        let m = m.into();
+        for p in self.values() {
+            if let Err(e) = p.conn.tx.send(m.clone()) {
+                println!("E: couldn't send {:?} to {p}: {e}", *m);
+            }
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_match_only_themselves() {
+        let pw = RoomPassword::new("hunter2");
+        assert!(pw.matches("hunter2"));
+        assert!(!pw.matches("hunter3") && !pw.matches(""));
+        assert_ne!(RoomPassword::new("hunter2").hash, pw.hash, "salted");
+    }
+}
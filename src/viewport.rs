@@ -0,0 +1,277 @@
+use std::sync::{ Arc, Mutex, OnceLock, Weak };
+use warp::ws::Message;
+use crate::minesweeper::{ Board, BoardConf, BoardPos };
+use crate::outbox::Frame;
+use crate::world::View;
+
+// Big boards don't go out whole: everyone gets the part they're looking at,
+// and after that only the tiles in it that changed. Whoever's looking at the
+// same thing shares the render, and what goes out for it is only made once.
+
+/// Past this share of the region changing, a whole new region is smaller than the delta
+pub const DELTA_SHARE: usize = 8;
+
+/// A rectangle of the board, in tiles, always within it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+impl Region {
+    pub fn whole(board: &Board) -> Self {
+        Region { x: 0, y: 0, w: board.width.get(), h: board.height.get() }
+    }
+    /// Corners in livepos coordinates, which span the whole board, none if it is the whole board
+    pub fn normalized(&self, board: &Board) -> Option<[u32; 4]> {
+        if *self == Region::whole(board) { return None; }
+        let scale = |v: usize, n: usize| ((v as u64) << 32).checked_div(n as u64).unwrap_or(0).min(u32::MAX as u64) as u32;
+        let (bw, bh) = (board.width.get(), board.height.get());
+        Some([scale(self.x, bw), scale(self.y, bh), scale(self.x + self.w, bw), scale(self.y + self.h, bh)])
+    }
+    /// A tile given relative to the region, as clients send them, on the whole board. None if it's outside
+    pub fn to_board(&self, pos: BoardPos) -> Option<BoardPos> {
+        let (x, y): (isize, isize) = pos.try_into().ok()?;
+        let (x, y) = (x as usize, y as usize);
+        (x < self.w && y < self.h).then(|| (self.x + x, self.y + y).try_into().ok()).flatten()
+    }
+    /// The part of `view` that's on the board, slid back in if it hangs off an edge
+    pub fn clamped(view: &View, board: &Board) -> Self {
+        let (bw, bh) = (board.width.get(), board.height.get());
+        let (w, h) = (view.w.min(bw), view.h.min(bh));
+        let x = view.x.clamp(0, (bw - w) as i64) as usize;
+        let y = view.y.clamp(0, (bh - h) as i64) as usize;
+        Region { x, y, w, h }
+    }
+}
+
+/// A region of the board as rendered, and what's been made of it so far
+#[derive(Debug)]
+pub struct Render {
+    pub bytes: Vec<u8>,
+    deflated: OnceLock<Frame>,
+    /// deltas from earlier renders, none where they're too big to be worth it
+    deltas: Mutex<Vec<(Weak<Render>, Option<Frame>)>>,
+}
+
+impl Render {
+    pub fn new(bytes: Vec<u8>) -> Arc<Self> {
+        Arc::new(Render { bytes, deflated: OnceLock::new(), deltas: Mutex::new(vec![]) })
+    }
+
+    /// The whole thing, as clients expect it
+    pub fn frame(&self) -> Frame {
+        self.deflated.get_or_init(|| Arc::new(crate::gameloop::deflated(&self.bytes))).clone()
+    }
+
+    /// `delta [[index, byte], ...]` from `last`, if little enough changed
+    fn delta(&self, last: &Arc<Render>) -> Option<Frame> {
+        let mut deltas = self.deltas.lock().unwrap();
+        if let Some((_, d)) = deltas.iter().find(|(r, _)| r.as_ptr() == Arc::as_ptr(last)) {
+            return d.clone();
+        }
+        let changed = last.bytes.iter().zip(self.bytes.iter()).enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, (_, b))| (i, *b))
+            .collect::<Vec<_>>();
+        let d = (changed.len() * DELTA_SHARE < self.bytes.len()).then(|| {
+            let delta = serde_json::to_string(&changed).expect("couldn't JSONify board delta");
+            Arc::new(Message::text(format!("delta {delta}")))
+        });
+        deltas.push((Arc::downgrade(last), d.clone()));
+        d
+    }
+}
+
+/// What it takes to bring a viewport up to date
+#[derive(Debug)]
+pub enum ViewUpdate {
+    /// they already have it
+    Nothing,
+    /// a few tiles changed
+    Delta(Frame),
+    /// the region's shape and where it sits, then the region itself
+    Whole(Vec<Frame>, Frame),
+}
+
+/// What one connection wants to see of the board and what it was last sent of it
+#[derive(Debug, Default)]
+pub struct Viewport {
+    /// what they asked for, if anything
+    pub view: Option<View>,
+    last: Option<(Region, Arc<Render>)>,
+}
+
+impl Viewport {
+    /// The region to send, the whole board if it fits in `max_area` and nobody asked otherwise
+    pub fn region(&self, board: &Board, max_area: usize) -> Region {
+        match &self.view {
+            Some(v) => Region::clamped(v, board),
+            None if board.width.get() * board.height.get() <= max_area => Region::whole(board),
+            None => {
+                let w = board.width.get().min((max_area as f64).sqrt() as usize).max(1);
+                let h = board.height.get().min(max_area / w).max(1);
+                Region { x: 0, y: 0, w, h }
+            },
+        }
+    }
+
+    /// Forget what was sent, the next update goes out whole
+    pub fn invalidate(&mut self) {
+        self.last = None;
+    }
+
+    /// Change a few tiles of what they have of `region`, given as board offsets and
+    /// what they render as. None if they don't have the region, it needs sending whole
+    pub fn patch(&mut self, board: &Board, region: Region, tiles: impl IntoIterator<Item = (usize, u8)>) -> Option<ViewUpdate> {
+        let (last_region, last) = self.last.as_ref().filter(|(r, _)| *r == region)?;
+        let w = board.width.get();
+        let mut bytes = last.bytes.clone();
+        let mut changed = vec![];
+        for (off, b) in tiles {
+            let (x, y) = (off % w, off / w);
+            if x < last_region.x || y < last_region.y || x >= last_region.x + last_region.w || y >= last_region.y + last_region.h { continue; }
+            let i = (y - last_region.y) * (last_region.w + 4) + (x - last_region.x);
+            if bytes[i] != b {
+                bytes[i] = b;
+                changed.push((i, b));
+            }
+        }
+        if changed.is_empty() { return Some(ViewUpdate::Nothing); }
+        changed.sort_unstable();
+        changed.dedup();
+        let delta = serde_json::to_string(&changed).expect("couldn't JSONify board delta");
+        self.last = Some((region, Render::new(bytes)));
+        Some(ViewUpdate::Delta(Arc::new(Message::text(format!("delta {delta}")))))
+    }
+
+    /// What to send for `rendered`, a render of `region`: nothing if it's what they
+    /// already have, a delta into the last one if little changed, and otherwise its
+    /// shape, where it sits and the region itself
+    pub fn update(&mut self, board: &Board, conf: &BoardConf, region: Region, rendered: Arc<Render>) -> ViewUpdate {
+        match &self.last {
+            Some((last_region, last)) if *last_region == region && last.bytes.len() == rendered.bytes.len() => {
+                if Arc::ptr_eq(last, &rendered) || last.bytes == rendered.bytes { return ViewUpdate::Nothing; }
+                if let Some(delta) = rendered.delta(last) {
+                    self.last = Some((region, rendered));
+                    return ViewUpdate::Delta(delta);
+                }
+            },
+            _ => (),
+        }
+        let (n, d) = conf.mine_ratio;
+        let shape = vec![
+            Message::text(format!("bconf {}x{} {n}/{d}", region.w, region.h)).into(),
+            Message::text(if region == Region::whole(board) {
+                "view -".to_string()
+            } else {
+                format!("view {} {} {} {} {} {}", region.x, region.y, region.w, region.h, board.width, board.height)
+            }).into(),
+        ];
+        let whole = rendered.frame();
+        self.last = Some((region, rendered));
+        ViewUpdate::Whole(shape, whole)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroUsize;
+    use crate::minesweeper::{ FlagScope, Game, Move, MoveType, Sight };
+
+    fn conf(w: usize, h: usize, fog_radius: Option<usize>) -> BoardConf {
+        BoardConf {
+            w: NonZeroUsize::new(w).unwrap(), h: NonZeroUsize::new(h).unwrap(),
+            mine_ratio: (1, NonZeroUsize::new(6).unwrap()),
+            always_safe_first_move: false, revealed_borders: false, reveal_on_lose: false, num_tile_reveal: false,
+            flag_scope: FlagScope::Shared, fog_radius, hardcore_lives: None,
+        }
+    }
+    /// Livepos coordinates of the middle of tile `(x, y)`
+    fn cursor_on(x: usize, y: usize, g: &Game) -> (u32, u32) {
+        let mid = |v: usize, n: usize| (((2 * v as u64 + 1) << 31) / n as u64) as u32;
+        (mid(x, g.board.width.get()), mid(y, g.board.height.get()))
+    }
+
+    #[test]
+    fn fog_patches_match_full_renders() {
+        let mut g = Game::with_seed(conf(30, 20, Some(3)), 7);
+        // dig wherever's safe, so there's revealed ground for the fog to cover
+        for off in (0..g.board.area()).step_by(7) {
+            if g.board.is_mine_at(off) { continue; }
+            let pos = ((off % 30) as u32, (off / 30) as u32).try_into().unwrap();
+            g.act(Move { t: MoveType::Reveal, pos });
+        }
+        assert_eq!(g.phase, crate::minesweeper::Phase::Run);
+        let region = Region { x: 5, y: 2, w: 20, h: 15 };
+        let mut sight = Sight::default();
+        let mut vp = Viewport::default();
+        vp.update(&g.board, &g.board_conf, region, Render::new(g.render_for(None, Some(&sight), region)));
+        let mut deltas = 0;
+        for (x, y) in (0..30).map(|i| (i, (i * 7) % 20)) {
+            let mut lit = sight.around_cursor(&g.board, 3);
+            sight.look_at(cursor_on(x, y, &g), &g.board);
+            lit.extend(sight.around_cursor(&g.board, 3));
+            let tiles = lit.into_iter().map(|off| (off, g.render_tile_for(None, Some(&sight), off)));
+            if let ViewUpdate::Delta(_) = vp.patch(&g.board, region, tiles).unwrap() { deltas += 1; }
+            let full = g.render_for(None, Some(&sight), region);
+            assert_eq!(vp.last.as_ref().unwrap().1.bytes, full, "cursor on {x},{y}");
+        }
+        assert!(deltas > 0);
+    }
+
+    #[test]
+    fn patch_needs_the_region_sent_first() {
+        let g = Game::with_seed(conf(10, 10, None), 1);
+        let mut vp = Viewport::default();
+        let region = Region::whole(&g.board);
+        assert!(vp.patch(&g.board, region, [(0, b'F')]).is_none());
+        vp.update(&g.board, &g.board_conf, region, Render::new(g.board.render()));
+        assert!(vp.patch(&g.board, Region { x: 1, ..region }, [(0, b'F')]).is_none());
+        assert!(matches!(vp.patch(&g.board, region, [(0, b'#')]), Some(ViewUpdate::Nothing)));
+        assert!(matches!(vp.patch(&g.board, region, [(0, b'F')]), Some(ViewUpdate::Delta(_))));
+    }
+
+    #[test]
+    fn updates_send_deltas_only_for_small_changes() {
+        let g = Game::with_seed(conf(20, 20, None), 3);
+        let region = Region::whole(&g.board);
+        let mut vp = Viewport::default();
+        let first = Render::new(g.board.render());
+        assert!(matches!(vp.update(&g.board, &g.board_conf, region, first.clone()), ViewUpdate::Whole(..)));
+        assert!(matches!(vp.update(&g.board, &g.board_conf, region, first.clone()), ViewUpdate::Nothing));
+
+        let mut bytes = first.bytes.clone();
+        bytes[0] = b'F';
+        bytes[25] = b'F';
+        let ViewUpdate::Delta(d) = vp.update(&g.board, &g.board_conf, region, Render::new(bytes.clone())) else { panic!("no delta") };
+        assert_eq!(d.to_str().unwrap(), "delta [[0,70],[25,70]]");
+
+        bytes.iter_mut().filter(|b| **b == b'#').for_each(|b| *b = b' ');
+        assert!(matches!(vp.update(&g.board, &g.board_conf, region, Render::new(bytes)), ViewUpdate::Whole(..)), "too much changed");
+    }
+
+    #[test]
+    fn new_regions_go_out_whole() {
+        let g = Game::with_seed(conf(40, 40, None), 3);
+        let mut vp = Viewport::default();
+        let a = Region { x: 0, y: 0, w: 10, h: 10 };
+        let b = Region { x: 5, ..a };
+        vp.update(&g.board, &g.board_conf, a, Render::new(g.render_for(None, None, a)));
+        let ViewUpdate::Whole(shape, _) = vp.update(&g.board, &g.board_conf, b, Render::new(g.render_for(None, None, b))) else { panic!("not whole") };
+        assert_eq!(shape[1].to_str().unwrap(), "view 5 0 10 10 40 40");
+    }
+
+    #[test]
+    fn moves_land_where_the_region_is() {
+        let region = Region { x: 5, y: 7, w: 10, h: 10 };
+        let pos = |x: u32, y: u32| BoardPos::try_from((x, y)).unwrap();
+        let to = |p: BoardPos| -> (isize, isize) { p.try_into().unwrap() };
+        assert_eq!(region.to_board(pos(0, 0)).map(to), Some((5, 7)));
+        assert_eq!(region.to_board(pos(9, 9)).map(to), Some((14, 16)));
+        assert!(region.to_board(pos(10, 0)).is_none());
+    }
+}
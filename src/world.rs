@@ -0,0 +1,546 @@
+use std::{
+    collections::{ HashMap, HashSet, VecDeque },
+    convert::TryInto,
+    net::SocketAddr,
+};
+use tokio::time::Instant;
+use warp::ws::Message;
+use crate::types::*;
+use crate::outbox::Frame;
+use crate::minesweeper::{ self, Move, MoveType, GameSummary, Phase };
+use crate::livepos::LivePos;
+use crate::room::Mode;
+use crate::viewport::DELTA_SHARE;
+
+// Endless rooms: a world with no edges, made of chunks that only come to be
+// when someone touches them. Whether a tile holds a mine is a hash of the
+// room's seed, the tile and its chunk's generation, so nothing has to be laid
+// out ahead of time. Blowing up only starts the neighbourhood over.
+// Moves come in relative to the mover's view, like on a regular board.
+
+pub const CHUNK: i64 = 32;
+/// how far out views may go, nobody pans this far and the arithmetic stays clear of overflows
+pub const REACH: i64 = 1 << 48;
+// most tiles a single click gets to flood, openings can go on forever at low densities
+const FLOOD_CAP: usize = 1 << 14;
+// chunks kept per world, a KiB apiece. Past it, the ones nobody's looking at that
+// were touched longest ago are forgotten, and grow back untouched
+const MAX_CHUNKS: usize = 1 << 12;
+
+const REVEALED: u8 = 1;
+const FLAGGED: u8 = 2;
+const QUESTION: u8 = 3;
+
+type ChunkId = (i64, i64);
+
+fn chunk_of(x: i64, y: i64) -> ChunkId {
+    (x.div_euclid(CHUNK), y.div_euclid(CHUNK))
+}
+
+/// What's been done to one chunk, mines aren't stored
+struct Chunk {
+    state: Vec<u8>,
+    /// `World::clock` when it was last changed
+    touched: u64,
+}
+
+impl Chunk {
+    fn new() -> Self {
+        Chunk { state: vec![0; (CHUNK * CHUNK) as usize], touched: 0 }
+    }
+}
+
+/// The rectangle of the world a player is looking at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct View {
+    pub x: i64,
+    pub y: i64,
+    pub w: usize,
+    pub h: usize,
+}
+
+impl View {
+    /// Chunks it covers, corners included
+    fn chunks(&self) -> (ChunkId, ChunkId) {
+        (chunk_of(self.x, self.y), chunk_of(self.x + self.w as i64 - 1, self.y + self.h as i64 - 1))
+    }
+    fn sees_chunk(&self, c: ChunkId) -> bool {
+        let ((x0, y0), (x1, y1)) = self.chunks();
+        (x0..=x1).contains(&c.0) && (y0..=y1).contains(&c.1)
+    }
+}
+
+pub struct World {
+    seed: u64,
+    /// mines/tiles, like `BoardConf::mine_ratio`
+    density: (u64, u64),
+    num_tile_reveal: bool,
+    chunks: HashMap<ChunkId, Chunk>,
+    /// bumped on every explosion in the chunk, so it gets new mines
+    generations: HashMap<ChunkId, u64>,
+    /// moves made, to tell which chunks were touched last
+    clock: u64,
+    /// chunks changed since they were last taken, see `take_dirty`
+    dirty: HashSet<ChunkId>,
+}
+
+/// What a move did to the world
+#[derive(Debug, Default)]
+pub struct WorldOutcome {
+    pub revealed: usize,
+    /// where it went off
+    pub exploded: Option<(i64, i64)>,
+}
+
+fn splitmix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl World {
+    pub fn new(conf: &minesweeper::BoardConf, seed: u64) -> Self {
+        World {
+            seed,
+            density: (conf.mine_ratio.0 as u64, conf.mine_ratio.1.get() as u64),
+            num_tile_reveal: conf.num_tile_reveal,
+            chunks: HashMap::new(),
+            generations: HashMap::new(),
+            clock: 0,
+            dirty: HashSet::new(),
+        }
+    }
+
+    pub fn is_mine(&self, x: i64, y: i64) -> bool {
+        let gen = self.generations.get(&chunk_of(x, y)).copied().unwrap_or(0);
+        let h = splitmix(self.seed ^ splitmix(x as u64 ^ splitmix(y as u64 ^ splitmix(gen))));
+        h % self.density.1 < self.density.0
+    }
+
+    fn neighs(x: i64, y: i64) -> impl Iterator<Item = (i64, i64)> {
+        (-1..=1).flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+            .filter(move |p| *p != (x, y))
+    }
+
+    fn number(&self, x: i64, y: i64) -> usize {
+        Self::neighs(x, y).filter(|(nx, ny)| self.is_mine(*nx, *ny)).count()
+    }
+
+    fn state(&self, x: i64, y: i64) -> u8 {
+        let (cx, cy) = chunk_of(x, y);
+        self.chunks.get(&(cx, cy))
+            .map(|c| c.state[((y - cy * CHUNK) * CHUNK + (x - cx * CHUNK)) as usize])
+            .unwrap_or(0)
+    }
+
+    fn state_mut(&mut self, x: i64, y: i64) -> &mut u8 {
+        let (cx, cy) = chunk_of(x, y);
+        self.dirty.insert((cx, cy));
+        let chunk = self.chunks.entry((cx, cy)).or_insert_with(Chunk::new);
+        chunk.touched = self.clock;
+        &mut chunk.state[((y - cy * CHUNK) * CHUNK + (x - cx * CHUNK)) as usize]
+    }
+
+    /// Chunks changed since the last call
+    pub fn take_dirty(&mut self) -> HashSet<ChunkId> {
+        std::mem::take(&mut self.dirty)
+    }
+
+    /// Forget the stalest chunks past `MAX_CHUNKS`, leaving alone the ones in `views`,
+    /// and the new mines of blasts nobody could tell from the old ones anymore
+    pub fn trim<'a>(&mut self, views: impl Iterator<Item = &'a View> + Clone) {
+        if self.chunks.len() > MAX_CHUNKS {
+            let mut unseen = self.chunks.iter()
+                .filter(|(id, _)| !views.clone().any(|v| v.sees_chunk(**id)))
+                .map(|(id, c)| (c.touched, *id))
+                .collect::<Vec<_>>();
+            // a quarter under the cap, so it isn't back here on the next move
+            let excess = (self.chunks.len() - MAX_CHUNKS * 3 / 4).min(unseen.len());
+            if excess > 0 {
+                unseen.select_nth_unstable(excess - 1);
+                for (_, id) in &unseen[..excess] {
+                    self.chunks.remove(id);
+                }
+            }
+        }
+        if self.generations.len() > self.chunks.len().max(MAX_CHUNKS / 4) {
+            // nothing around them revealed and nobody looking, the old mines would do as well
+            let chunks = &self.chunks;
+            self.generations.retain(|&(cx, cy), _| {
+                views.clone().any(|v| v.sees_chunk((cx, cy)))
+                    || (-1..=1).any(|dy| (-1..=1).any(|dx| chunks.contains_key(&(cx + dx, cy + dy))))
+            });
+        }
+    }
+
+    pub fn act(&mut self, m: Move, x: i64, y: i64) -> WorldOutcome {
+        self.clock += 1;
+        match m.t {
+            MoveType::ToggleFlag => {
+                let s = self.state_mut(x, y);
+                *s = match *s {
+                    0 => FLAGGED,
+                    FLAGGED => QUESTION,
+                    QUESTION => 0,
+                    revealed => revealed,
+                };
+                WorldOutcome::default()
+            },
+            MoveType::Reveal => {
+                let n = self.number(x, y);
+                let outcome = if self.num_tile_reveal && self.state(x, y) == REVEALED && n > 0 {
+                    self.reveal_around(x, y, n)
+                } else {
+                    self.flood(x, y)
+                };
+                if let Some((bx, by)) = outcome.exploded {
+                    self.blow_up(bx, by);
+                }
+                outcome
+            },
+        }
+    }
+
+    /// Number tile revealing, like `Board::reveal_numtile`
+    fn reveal_around(&mut self, x: i64, y: i64, n: usize) -> WorldOutcome {
+        let flags = Self::neighs(x, y).filter(|(nx, ny)| self.state(*nx, *ny) == FLAGGED).count();
+        let mut total = WorldOutcome::default();
+        if flags != n { return total; }
+        for (nx, ny) in Self::neighs(x, y) {
+            let o = self.flood(nx, ny);
+            total.revealed += o.revealed;
+            if o.exploded.is_some() {
+                total.exploded = o.exploded;
+                break;
+            }
+        }
+        total
+    }
+
+    fn flood(&mut self, x: i64, y: i64) -> WorldOutcome {
+        let mut outcome = WorldOutcome::default();
+        let mut queue = VecDeque::from([(x, y)]);
+        let mut queued = HashSet::from([(x, y)]);
+        while let Some((x, y)) = queue.pop_front() {
+            // flags stay shut, question marks don't
+            let s = self.state(x, y);
+            if s == REVEALED || s == FLAGGED { continue; }
+            if self.is_mine(x, y) {
+                outcome.exploded = Some((x, y));
+                break;
+            }
+            *self.state_mut(x, y) = REVEALED;
+            outcome.revealed += 1;
+            if outcome.revealed >= FLOOD_CAP { break; }
+            if self.number(x, y) > 0 { continue; }
+            for n in Self::neighs(x, y) {
+                if queued.insert(n) { queue.push_back(n); }
+            }
+        }
+        outcome
+    }
+
+    /// New mines where it went off, and everything around it hidden again
+    fn blow_up(&mut self, x: i64, y: i64) {
+        let (cx, cy) = chunk_of(x, y);
+        *self.generations.entry((cx, cy)).or_insert(0) += 1;
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                self.chunks.remove(&(cx + dx, cy + dy));
+                self.dirty.insert((cx + dx, cy + dy));
+            }
+        }
+    }
+
+    /// Same format as `Board::render`, for the tiles in `view`
+    pub fn render(&self, view: &View) -> Vec<u8> {
+        let mut ret = vec![];
+        for y in view.y..view.y + view.h as i64 {
+            for x in view.x..view.x + view.w as i64 {
+                ret.push(self.render_tile(x, y));
+            }
+            ret.extend_from_slice(b"<br>");
+        }
+        ret
+    }
+
+    fn render_tile(&self, x: i64, y: i64) -> u8 {
+        match self.state(x, y) {
+            REVEALED if self.is_mine(x, y) => b'O',
+            REVEALED => match self.number(x, y) {
+                0 => b' ',
+                n => b'0' + n as u8,
+            },
+            FLAGGED => b'F',
+            QUESTION => b'Q',
+            _ => b'#',
+        }
+    }
+
+    /// The tiles of `view` in the `changed` chunks, indexed like `render`'s output
+    fn patch(&self, view: &View, changed: &HashSet<ChunkId>) -> Vec<(usize, u8)> {
+        let mut tiles = vec![];
+        for (cx, cy) in changed.iter().filter(|c| view.sees_chunk(**c)) {
+            let (x0, y0) = ((cx * CHUNK).max(view.x), (cy * CHUNK).max(view.y));
+            let (x1, y1) = (((cx + 1) * CHUNK).min(view.x + view.w as i64), ((cy + 1) * CHUNK).min(view.y + view.h as i64));
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let i = (y - view.y) as usize * (view.w + 4) + (x - view.x) as usize;
+                    tiles.push((i, self.render_tile(x, y)));
+                }
+            }
+        }
+        tiles
+    }
+}
+
+/// `renders` holds what's been made of each view so far, so lookers-on of the same part share it
+fn send_view(p: &Player, world: &World, view: &View, renders: &mut HashMap<View, Frame>) {
+    let shape = Message::text(format!("bconf {}x{} {}/{}", view.w, view.h, world.density.0, world.density.1));
+    let origin = Message::text(format!("view {} {} {} {}", view.x, view.y, view.w, view.h));
+    let board = renders.entry(*view)
+        .or_insert_with(|| std::sync::Arc::new(crate::gameloop::deflated(&world.render(view))))
+        .clone();
+    for m in [shape, origin] {
+        if let Err(e) = p.conn.tx.send(m) {
+            println!("couldn't send view to {p}: {e}");
+        }
+    }
+    if let Err(e) = p.conn.tx.send_board(board) {
+        println!("couldn't send world to {p}: {e}");
+    }
+}
+
+pub struct Endless {
+    world: World,
+    /// where everyone starts out looking, the middle of the world
+    home: View,
+    views: HashMap<SocketAddr, View>,
+    /// the view each player last got whole, deltas only make sense on top of it
+    sent: HashMap<SocketAddr, View>,
+    born: Instant,
+    digs: usize,
+}
+
+impl Endless {
+    pub fn new(room_conf: RoomConf) -> Self {
+        let bconf = room_conf.board_conf;
+        let world = World::new(&bconf, rand::random());
+        Endless {
+            world,
+            home: View {
+                x: -(bconf.w.get() as i64) / 2,
+                y: -(bconf.h.get() as i64) / 2,
+                w: bconf.w.get(),
+                h: bconf.h.get(),
+            },
+            views: HashMap::new(),
+            sent: HashMap::new(),
+            born: Instant::now(),
+            digs: 0,
+        }
+    }
+
+    /// Everyone gets what they're looking at, rendered once per view
+    fn send_views(&mut self, peers: &PlayerMap) {
+        let mut renders = HashMap::new();
+        for p in peers.values() {
+            let view = self.views.get(&p.conn.addr).unwrap_or(&self.home);
+            send_view(p, &self.world, view, &mut renders);
+            self.sent.insert(p.conn.addr, *view);
+        }
+    }
+
+    /// Whoever's looking at a part that changed gets the changes, made once per view
+    fn send_changes(&mut self, peers: &PlayerMap) {
+        let changed = self.world.take_dirty();
+        if changed.is_empty() { return; }
+        let mut deltas: HashMap<View, Option<Frame>> = HashMap::new();
+        let mut renders = HashMap::new();
+        for p in peers.values() {
+            let view = *self.views.get(&p.conn.addr).unwrap_or(&self.home);
+            if self.sent.get(&p.conn.addr) != Some(&view) {
+                send_view(p, &self.world, &view, &mut renders);
+                self.sent.insert(p.conn.addr, view);
+                continue;
+            }
+            let world = &self.world;
+            let delta = deltas.entry(view).or_insert_with(|| {
+                let tiles = world.patch(&view, &changed);
+                (!tiles.is_empty() && tiles.len() * DELTA_SHARE < (view.w + 4) * view.h).then(|| {
+                    let delta = serde_json::to_string(&tiles).expect("couldn't JSONify world delta");
+                    std::sync::Arc::new(Message::text(format!("delta {delta}")))
+                })
+            });
+            match delta {
+                Some(d) => if let Err(e) = p.conn.tx.send_delta(d.clone()) {
+                    println!("couldn't send world changes to {p}: {e}");
+                },
+                // too much changed for a delta, or nothing they can see
+                None if view_changed(&view, &changed) => send_view(p, &self.world, &view, &mut renders),
+                None => (),
+            }
+        }
+    }
+}
+
+fn view_changed(view: &View, changed: &HashSet<ChunkId>) -> bool {
+    changed.iter().any(|c| view.sees_chunk(*c))
+}
+
+impl Mode for Endless {
+    fn handle(&mut self, req: MetaMove, peers: &PlayerMap, _livepos: &mut LivePos) {
+        match req {
+            MetaMove::Move(m, o) => {
+                let view = *self.views.entry(o).or_insert(self.home);
+                let (lx, ly): (isize, isize) = m.pos.try_into().unwrap();
+                if lx as usize >= view.w || ly as usize >= view.h { return; }
+                let outcome = self.world.act(m, view.x + lx as i64, view.y + ly as i64);
+                self.digs += outcome.revealed;
+                if let Some((x, y)) = outcome.exploded {
+                    let who = peers.get(&o).map(|p| p.name.clone()).unwrap_or_default();
+                    peers.broadcast(Message::text(format!("blast {}", serde_json::json!({ "player": who, "x": x, "y": y }))));
+                }
+            },
+            MetaMove::View(o, view) => {
+                self.views.insert(o, view);
+                if let Some(p) = peers.get(&o) {
+                    send_view(p, &self.world, &view, &mut HashMap::new());
+                    self.sent.insert(o, view);
+                }
+            },
+            MetaMove::StateDump => self.send_views(peers),
+            MetaMove::PlayersChanged => {
+                self.views.retain(|addr, _| peers.contains_key(addr));
+                self.sent.retain(|addr, _| peers.contains_key(addr));
+            },
+            MetaMove::Reset => (), // there's no end to start over from
+            MetaMove::Reconf(_) => unreachable!(),
+            MetaMove::Cursor(..) => (),
+        }
+    }
+
+    fn flush(&mut self, peers: &PlayerMap, _livepos: &mut LivePos) {
+        self.send_changes(peers);
+        let home = std::iter::once(&self.home);
+        self.world.trim(self.views.values().chain(home));
+    }
+
+    /// The world's there for good, so there's never a next board to set up
+    fn takes_settings(&self) -> bool { false }
+
+    /// Everyone's looking at a different part, so where their cursor is means nothing to anyone else
+    fn shares_cursors(&self) -> bool { false }
+
+    fn summary(&self) -> GameSummary {
+        let time = self.born.elapsed().as_secs_f64();
+        GameSummary { phase: Phase::Run, time, bbbv: 0, bbbv_per_s: 0.0, clicks: self.digs, efficiency: 0.0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroUsize;
+
+    fn world(n: usize, d: usize) -> World {
+        let conf = minesweeper::BoardConf {
+            w: NonZeroUsize::new(40).unwrap(), h: NonZeroUsize::new(30).unwrap(),
+            mine_ratio: (n, NonZeroUsize::new(d).unwrap()),
+            always_safe_first_move: false, revealed_borders: false, reveal_on_lose: false, num_tile_reveal: false,
+            flag_scope: minesweeper::FlagScope::Shared, fog_radius: None, hardcore_lives: None,
+        };
+        World::new(&conf, 42)
+    }
+    fn mv(t: MoveType) -> Move {
+        Move { t, pos: (0, 0).try_into().unwrap() }
+    }
+
+    #[test]
+    fn flood_is_capped_on_an_empty_world() {
+        let mut w = world(0, 1);
+        let o = w.act(mv(MoveType::Reveal), -5, 7);
+        assert_eq!(o.revealed, FLOOD_CAP);
+        assert!(o.exploded.is_none());
+    }
+
+    #[test]
+    fn flood_stops_at_numbers_and_flags() {
+        let mut w = world(1, 5);
+        w.act(mv(MoveType::ToggleFlag), 3, 3);
+        // somewhere with no mines around to start from
+        let (x, y) = (0..).map(|i| (i, 0)).find(|(x, y)| !w.is_mine(*x, *y) && w.number(*x, *y) == 0).unwrap();
+        let o = w.act(mv(MoveType::Reveal), x, y);
+        assert!(o.revealed > 0 && o.exploded.is_none());
+        assert_eq!(w.state(3, 3), FLAGGED);
+        for (cx, cy) in w.chunks.keys().copied().collect::<Vec<_>>() {
+            for y in cy * CHUNK..(cy + 1) * CHUNK {
+                for x in cx * CHUNK..(cx + 1) * CHUNK {
+                    if w.state(x, y) != REVEALED { continue; }
+                    assert!(!w.is_mine(x, y));
+                    // whatever's next to an opened-up zero got opened up too
+                    if w.number(x, y) == 0 {
+                        assert!(World::neighs(x, y).all(|(nx, ny)| w.state(nx, ny) == REVEALED || w.state(nx, ny) == FLAGGED));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blasts_start_the_neighbourhood_over() {
+        let mut w = world(1, 1);
+        let o = w.act(mv(MoveType::Reveal), 10, 10);
+        assert_eq!(o.exploded, Some((10, 10)));
+        assert_eq!(w.state(10, 10), 0);
+        assert_eq!(w.generations[&chunk_of(10, 10)], 1);
+        assert!(w.take_dirty().contains(&chunk_of(10 + CHUNK, 10)));
+    }
+
+    #[test]
+    fn trimming_keeps_what_is_in_view() {
+        let mut w = world(0, 1);
+        for i in 0..MAX_CHUNKS as i64 + 10 {
+            w.clock += 1;
+            *w.state_mut(i * CHUNK, 0) = FLAGGED;
+        }
+        let view = View { x: 0, y: 0, w: 10, h: 10 };
+        w.trim([view].iter());
+        assert!(w.chunks.len() <= MAX_CHUNKS);
+        assert_eq!(w.state(0, 0), FLAGGED, "in view, oldest or not");
+        assert_eq!(w.state((MAX_CHUNKS as i64 + 9) * CHUNK, 0), FLAGGED, "touched last");
+        assert_eq!(w.state(CHUNK, 0), 0, "stale and unseen");
+    }
+
+    #[test]
+    fn patches_line_up_with_renders() {
+        let mut w = world(1, 6);
+        let view = View { x: -50, y: -20, w: 70, h: 45 };
+        let before = w.render(&view);
+        w.take_dirty();
+        w.act(mv(MoveType::ToggleFlag), -3, 2);
+        let (x, y) = (1..).map(|i| (i, i)).find(|(x, y)| !w.is_mine(*x, *y) && w.number(*x, *y) == 0).unwrap();
+        w.act(mv(MoveType::Reveal), x, y);
+        let mut patched = before;
+        let changed = w.take_dirty();
+        for (i, b) in w.patch(&view, &changed) {
+            patched[i] = b;
+        }
+        assert_eq!(patched, w.render(&view));
+    }
+
+    #[test]
+    fn trimming_forgets_blasts_nobody_could_tell_apart() {
+        let mut w = world(1, 6);
+        for i in 0..MAX_CHUNKS as i64 + 10 {
+            w.generations.insert((i * 3, 50), 1);
+        }
+        *w.state_mut(0, 50 * CHUNK) = FLAGGED;
+        let view = View { x: 30 * CHUNK, y: 50 * CHUNK, w: 10, h: 10 };
+        w.trim([view].iter());
+        assert_eq!(w.generations.len(), 2);
+        assert!(w.generations.contains_key(&(0, 50)), "next to something revealed");
+        assert!(w.generations.contains_key(&(30, 50)), "in view");
+    }
+}